@@ -0,0 +1,227 @@
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::{Command, Mock};
+use crate::request::Request;
+use crate::server::{Position, Server};
+
+/// Requests starting with this path are handled by the server itself,
+/// they are never matched against the mocks
+pub(crate) const PREFIX: &str = "/__mockside/";
+
+pub(crate) fn is_admin(target: &str) -> bool {
+    target.starts_with(PREFIX) || target == PREFIX.trim_end_matches('/')
+}
+
+/// Answers an admin request, the profile and the timers are the ones of its session
+pub(crate) fn handle(request: &Request, session: Option<&str>, server: &Server) -> Vec<u8> {
+    let (path, query) = match request.target.find('?') {
+        Some(i) => (&request.target[..i], &request.target[i + 1..]),
+        None => (&request.target[..], ""),
+    };
+    let path = path.get(PREFIX.len()..).unwrap_or_default().trim_end_matches('/');
+    let contains = query_param(query, "contains").unwrap_or_default();
+
+    match (request.method.as_str(), path) {
+        ("GET", "requests") => {
+            let entries: Vec<Value> = server.journal.find(&contains).iter().map(|e| e.to_json()).collect();
+            json_response("200 OK", &Value::Array(entries))
+        },
+        ("GET", "requests/count") => json_response("200 OK", &json!({ "count": server.journal.count(&contains) })),
+        ("DELETE", "requests") => json_response("200 OK", &json!({ "cleared": server.journal.clear() })),
+        ("GET", "sessions") => {
+            let sessions: Vec<(String, isize)> = {
+                let sessions = server.state.lock().unwrap();
+                sessions.sessions().iter().map(|(id, state)| (id.to_string(), state.profile)).collect()
+            };
+            // the profile names are looked up without holding the state
+            let sessions: Vec<Value> = sessions.into_iter()
+                .map(|(id, profile)| json!({ "session": id, "profile": server.profile_name(profile) }))
+                .collect();
+            json_response("200 OK", &Value::Array(sessions))
+        },
+        ("DELETE", "sessions") => json_response("200 OK", &json!({ "cleared": server.state.lock().unwrap().clear() })),
+        ("GET", "profile") => {
+            json_response("200 OK", &json!({ "profile": server.profile(session) }))
+        },
+        ("PUT", "profile") | ("POST", "profile") => {
+            // the name is the body or the name parameter
+            let name = query_param(query, "name").unwrap_or_else(|| body_text(request));
+            match server.set_profile(session, &name) {
+                Ok(()) => json_response("200 OK", &json!({ "profile": name })),
+                Err(e) => json_response("404 Not Found", &json!({ "error": e.to_string() })),
+            }
+        },
+        ("POST", "reset") => {
+            // all the timers unless one is named
+            match query_param(query, "timer") {
+                Some(timer) => server.reset_named_timer(session, &timer),
+                None => server.reset_timer(session),
+            }
+            json_response("200 OK", &json!({ "reset": true }))
+        },
+        ("GET", "clock") => {
+            // the config snapshot is taken first, a reload locks the state while holding the config
+            let mocks = server.mocks();
+            let now = server.clock.now();
+            let sessions = server.state.lock().unwrap();
+            let state = sessions.get(session, now);
+            let elapsed = |timer: Option<&str>| now.saturating_sub(state.origin(timer)).as_millis() as u64;
+            // the named timers used by the mocks, reset or not
+            let timers: serde_json::Map<String, Value> = mocks.iter()
+                .filter_map(|m| m.timer.as_deref())
+                .chain(state.timers.keys().filter_map(|t| t.as_deref()))
+                .map(|t| (t.to_string(), json!(elapsed(Some(t)))))
+                .collect();
+            json_response("200 OK", &json!({
+                "virtual": server.clock.is_virtual(),
+                "time": now.as_millis() as u64,
+                "elapsed": elapsed(None),
+                "timers": timers,
+            }))
+        },
+        ("PUT", "clock") | ("POST", "clock") => {
+            // the mode is the body or the mode parameter
+            let mode = query_param(query, "mode").unwrap_or_else(|| body_text(request));
+            match mode.as_str() {
+                "virtual" | "real" => {
+                    server.clock.set_virtual(mode == "virtual");
+                    json_response("200 OK", &json!({ "virtual": server.clock.is_virtual() }))
+                },
+                _ => json_response("400 Bad Request", &json!({ "error": format!("Unknown clock mode {}, expected virtual or real", mode) })),
+            }
+        },
+        ("POST", "clock/advance") => {
+            // the milliseconds are the body or the ms parameter
+            let ms = query_param(query, "ms").unwrap_or_else(|| body_text(request));
+            match ms.parse() {
+                Ok(ms) => match server.advance_clock(Duration::from_millis(ms)) {
+                    Ok(()) => json_response("200 OK", &json!({ "time": server.clock.now().as_millis() as u64 })),
+                    Err(e) => json_response("409 Conflict", &json!({ "error": e.to_string() })),
+                },
+                Err(_) => json_response("400 Bad Request", &json!({ "error": format!("Invalid number of milliseconds {}", ms) })),
+            }
+        },
+        ("GET", "scenarios") => {
+            // the scenarios used by the mocks, the config snapshot is taken before the state
+            let mocks = server.mocks();
+            let sessions = server.state.lock().unwrap();
+            let state = sessions.get(session, server.clock.now());
+            let scenarios: serde_json::Map<String, Value> = mocks.iter()
+                .filter_map(|m| m.scenario.as_ref())
+                .map(|s| (s.name.clone(), json!(state.scenario_state(&s.name))))
+                .collect();
+            json_response("200 OK", &Value::Object(scenarios))
+        },
+        ("GET", "mocks") => {
+            // the hit counters of the session, the config snapshot is taken before the state
+            let mocks = server.mocks();
+            let hits = server.state.lock().unwrap().get(session, server.clock.now()).hits.clone();
+            let mocks: Vec<Value> = mocks.iter().map(|m| mock_json(m, &hits, server)).collect();
+            json_response("200 OK", &Value::Array(mocks))
+        },
+        ("POST", "mocks") => {
+            let position = match query_param(query, "position").as_deref() {
+                None | Some("front") => Position::Front,
+                Some("back") => Position::Back,
+                Some(index) => match index.parse() {
+                    Ok(index) => Position::At(index),
+                    Err(_) => return json_response("400 Bad Request", &json!({ "error": format!("Invalid position {}", index) })),
+                },
+            };
+            match server.add_mocks(&String::from_utf8_lossy(&request.body), position) {
+                Ok(ids) => json_response("201 Created", &json!({ "ids": ids })),
+                Err(e) => json_response("400 Bad Request", &json!({ "error": e.to_string() })),
+            }
+        },
+        ("DELETE", path) if path.starts_with("mocks/") => {
+            match path["mocks/".len()..].parse() {
+                Ok(id) if server.remove_mock(id) => json_response("200 OK", &json!({ "removed": id })),
+                _ => json_response("404 Not Found", &json!({ "error": format!("no mock with id {}", &path["mocks/".len()..]) })),
+            }
+        },
+        _ => json_response("404 Not Found", &json!({ "error": format!("no admin endpoint for {} {}", request.method, request.target) })),
+    }
+}
+
+fn mock_json(mock: &Mock, hits: &HashMap<usize, usize>, server: &Server) -> Value {
+    json!({
+        "id": mock.id,
+        "file": mock.file.as_ref().map(|f| f.display().to_string()),
+        "line_number": mock.line_number,
+        "criteria": mock.patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        "files": mock.filenames,
+        "command": format!("{:?}", mock.command).to_lowercase(),
+        "profile": server.profile_name(mock.profile),
+        "destination_profile": if mock.command == Command::Profile { Some(server.profile_name(mock.destination_profile)) } else { None },
+        "after": mock.time.map(|t| t.as_millis() as u64),
+        "timer": mock.timer,
+        "delay": mock.delay.map(|d| d.as_millis() as u64),
+        "times": mock.times,
+        "nth": mock.nth,
+        "scenario": mock.scenario.as_ref().map(|s| json!({ "name": s.name, "state": s.state, "next": s.next })),
+        "hits": if mock.times.is_some() || mock.nth.is_some() { Some(hits.get(&mock.id).copied().unwrap_or(0)) } else { None },
+    })
+}
+
+fn body_text(request: &Request) -> String {
+    String::from_utf8_lossy(&request.body).trim().to_string()
+}
+
+pub(crate) fn json_response(status: &str, value: &Value) -> Vec<u8> {
+    let body = value.to_string();
+    format!(
+        "HTTP/1.1 {}\r\nserver: iron-mockside\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    ).into_bytes()
+}
+
+/// Value of a parameter from the query string, percent decoded
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&')
+        .filter_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            Some((parts.next()?, parts.next().unwrap_or("")))
+        })
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high * 16 + low);
+                    i += 2;
+                },
+                _ => decoded.push(b'%'),
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn query() {
+        assert_eq!(Some(String::from("POST /payments")), super::query_param("a=1&contains=POST%20%2Fpayments", "contains"));
+        assert_eq!(Some(String::from("a b")), super::query_param("contains=a+b", "contains"));
+        assert_eq!(None, super::query_param("a=1", "contains"));
+        assert_eq!("100%", super::percent_decode("100%"));
+        assert_eq!("%zz", super::percent_decode("%zz"));
+        assert_eq!("%é", super::percent_decode("%é"));
+    }
+}
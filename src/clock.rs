@@ -0,0 +1,87 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The time the `after` mocks are measured with. It is the real time unless
+/// switched to virtual, then it only moves when advanced.
+#[derive(Debug)]
+pub(crate) struct Clock {
+    mode: Mutex<Mode>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// the time at `since` plus the real time passed from then
+    Real { base: Duration, since: Instant },
+    Virtual(Duration),
+}
+
+impl Clock {
+    pub(crate) fn new(virtual_clock: bool) -> Clock {
+        let mode = if virtual_clock {
+            Mode::Virtual(Duration::from_millis(0))
+        } else {
+            Mode::Real { base: Duration::from_millis(0), since: Instant::now() }
+        };
+        Clock { mode: Mutex::new(mode) }
+    }
+
+    /// Time since the clock was started
+    pub(crate) fn now(&self) -> Duration {
+        match *self.mode.lock().unwrap() {
+            Mode::Real { base, since } => base + since.elapsed(),
+            Mode::Virtual(time) => time,
+        }
+    }
+
+    pub(crate) fn is_virtual(&self) -> bool {
+        matches!(*self.mode.lock().unwrap(), Mode::Virtual(_))
+    }
+
+    /// Switches between the real and the virtual time, either continues from the current time
+    pub(crate) fn set_virtual(&self, virtual_clock: bool) {
+        let mut mode = self.mode.lock().unwrap();
+        *mode = match (virtual_clock, *mode) {
+            (true, Mode::Real { base, since }) => Mode::Virtual(base + since.elapsed()),
+            (false, Mode::Virtual(time)) => Mode::Real { base: time, since: Instant::now() },
+            (_, unchanged) => unchanged,
+        };
+    }
+
+    /// Moves the virtual time forward, returns false for the real clock
+    pub(crate) fn advance(&self, duration: Duration) -> bool {
+        match &mut *self.mode.lock().unwrap() {
+            Mode::Virtual(time) => {
+                *time += duration;
+                true
+            },
+            Mode::Real { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn virtual_time() {
+        let clock = super::Clock::new(true);
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(Duration::from_millis(0), clock.now());
+        assert!(clock.advance(Duration::from_millis(1500)));
+        assert_eq!(Duration::from_millis(1500), clock.now());
+
+        // the real time continues from the virtual one
+        clock.set_virtual(false);
+        assert!(!clock.is_virtual());
+        assert!(!clock.advance(Duration::from_millis(10)));
+        assert!(clock.now() >= Duration::from_millis(1500));
+        assert!(clock.now() < Duration::from_millis(2500));
+
+        clock.set_virtual(true);
+        let now = clock.now();
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(now, clock.now());
+    }
+}
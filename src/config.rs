@@ -0,0 +1,1668 @@
+use itertools::Itertools;
+use std::collections::{ HashMap, HashSet };
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::*;
+use regex::{Regex, RegexBuilder};
+use serde_json::{json, Value};
+
+pub(crate) const DEFAULT_PROFILE: isize = 0;
+pub(crate) const ANY_PROFILE: isize = -1;
+/// State every scenario starts in
+pub(crate) const INITIAL_STATE: &str = "start";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Command{ Serve, Delay, After, Reset, Profile, }
+
+/// A line of criteria from a mock group
+#[derive(Debug, Clone)]
+pub(crate) enum Criterion {
+    /// the request contains this text
+    Contains(String),
+    /// the request matches this regular expression, the line starts with ~
+    Matches(Regex),
+    /// the request doesn't meet the criterion, the line starts with !
+    Not(Box<Criterion>),
+    /// the request as received meets the criterion, with the chunked body not decoded,
+    /// the line starts with @
+    Wire(Box<Criterion>),
+}
+
+/// The request the criteria are matched against
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequestText<'a> {
+    /// with the body decoded and the trailers added to the headers
+    pub(crate) decoded: &'a str,
+    /// as received, differs from the decoded text only for a chunked body
+    pub(crate) wire: &'a str,
+}
+
+impl<'a> From<&'a str> for RequestText<'a> {
+    /// A request received without a transfer encoding
+    fn from(text: &'a str) -> RequestText<'a> {
+        RequestText { decoded: text, wire: text }
+    }
+}
+
+impl Criterion {
+    pub(crate) fn parse(line: &str) -> Result<Criterion, regex::Error> {
+        if let Some(negated) = line.strip_prefix('!') {
+            return Criterion::parse(negated).map(|c| Criterion::Not(Box::new(c)));
+        }
+        if let Some(wire) = line.strip_prefix('@') {
+            return Criterion::parse(wire).map(|c| Criterion::Wire(Box::new(c)));
+        }
+        match line.strip_prefix('~') {
+            Some(expression) => RegexBuilder::new(expression).multi_line(true).build().map(Criterion::Matches),
+            None => Ok(Criterion::Contains(line.to_string())),
+        }
+    }
+
+    pub(crate) fn is_met(&self, request: RequestText) -> bool {
+        match self {
+            Criterion::Contains(text) => request.decoded.contains(text.as_str()),
+            Criterion::Matches(regex) => regex.is_match(request.decoded),
+            Criterion::Not(criterion) => !criterion.is_met(request),
+            Criterion::Wire(criterion) => criterion.is_met(request.wire.into()),
+        }
+    }
+
+    /// Adds the named groups of a regular expression to the captured values
+    pub(crate) fn captures(&self, request: RequestText, values: &mut HashMap<String, String>) {
+        match self {
+            Criterion::Matches(regex) => if let Some(captures) = regex.captures(request.decoded) {
+                for name in regex.capture_names().flatten() {
+                    if let Some(value) = captures.name(name) {
+                        values.insert(name.to_string(), value.as_str().to_string());
+                    }
+                }
+            },
+            Criterion::Wire(criterion) => criterion.captures(request.wire.into(), values),
+            _ => (),
+        }
+    }
+
+    /// True if every request meeting the other criterion meets this one too.
+    /// Regular expressions are only compared by their text, so the answer
+    /// can be false even if the other one implies this one.
+    fn implied_by(&self, other: &Criterion) -> bool {
+        match (self, other) {
+            (Criterion::Contains(text), Criterion::Contains(other)) => other.contains(text.as_str()),
+            (Criterion::Matches(regex), Criterion::Matches(other)) => regex.as_str() == other.as_str(),
+            // not containing a text implies not containing any longer text that includes it
+            (Criterion::Not(criterion), Criterion::Not(other)) => other.implied_by(criterion),
+            (Criterion::Wire(criterion), Criterion::Wire(other)) => criterion.implied_by(other),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Criterion {
+    /// The criterion as written in the config file
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Criterion::Contains(text) => write!(f, "{}", text),
+            Criterion::Matches(regex) => write!(f, "~{}", regex.as_str()),
+            Criterion::Not(criterion) => write!(f, "!{}", criterion),
+            Criterion::Wire(criterion) => write!(f, "@{}", criterion),
+        }
+    }
+}
+
+/// A group from the config file: the criteria and the response
+#[derive(Debug, Clone)]
+pub struct Mock {
+    pub(crate) filenames: String,
+    pub(crate) patterns: Vec<Criterion>,
+    pub(crate) time: Option<Duration>,
+    /// timer of the `after` and `reset` commands, `None` for the default one
+    pub(crate) timer: Option<String>,
+    pub(crate) delay: Option<Duration>,
+    pub(crate) profile: isize,
+    pub(crate) destination_profile: isize,
+    pub(crate) command: Command,
+    /// compute the content-length header of the response
+    pub(crate) content_length: bool,
+    /// fill the placeholders of the response parts
+    pub(crate) template: bool,
+    /// the mock is used for the first matching requests only, the next ones fall through
+    pub(crate) times: Option<usize>,
+    /// the mock is used only for this matching request, counting from 1
+    pub(crate) nth: Option<usize>,
+    pub(crate) scenario: Option<Scenario>,
+    pub(crate) line_number: usize,
+    /// the file the group is in, `None` if the config was not read from a file
+    pub(crate) file: Option<PathBuf>,
+    /// the directory of the included file the group is in, its response files are relative to it.
+    /// `None` for the groups of the main config, relative to the base directory.
+    pub(crate) response_dir: Option<PathBuf>,
+    /// identifies the mock in the admin API, assigned by the server
+    pub(crate) id: usize,
+}
+
+impl Mock {
+    /// Line of the group in its config file, starts at 1
+    pub fn line(&self) -> usize {
+        self.line_number
+    }
+
+    /// The config file the group is in, `None` if the config was not read from a file
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    /// The criteria lines as written in the config
+    pub fn criteria(&self) -> Vec<String> {
+        self.patterns.iter().map(Criterion::to_string).collect()
+    }
+
+    /// The files making up the response in order, relative to the config file of the group
+    pub fn files(&self) -> Vec<&str> {
+        self.response_files().collect()
+    }
+
+    /// Where the group is in the config, for the messages
+    pub(crate) fn location(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}", file.display(), self.line_number),
+            None => format!("line {}", self.line_number),
+        }
+    }
+
+    /// An error about the whole group
+    fn error(&self, text: &str, message: String) -> ConfigError {
+        ConfigError { file: self.file.clone(), line: self.line_number, column: 1, text: text.to_string(), message }
+    }
+
+    /// The parts making up the response in order, without the instructions
+    pub(crate) fn response_parts(&self) -> Vec<Part<'_>> {
+        // the line was checked when the config was parsed
+        split_parts(&self.filenames).unwrap_or_default().into_iter()
+            .map(|(_, s)| s)
+            .filter(|s| !s.is_empty())
+            .skip_while(|s| s.starts_with('`'))
+            .filter_map(|s| Part::parse(s).ok())
+            .collect()
+    }
+
+    /// The files making up the response, without the instructions and the quoted text
+    pub(crate) fn response_files(&self) -> impl Iterator<Item = &str> {
+        self.response_parts().into_iter().filter_map(|p| match p {
+            Part::File(file) => Some(file),
+            Part::Text(_) => None,
+        })
+    }
+
+    /// Where a response file of the group is
+    pub(crate) fn response_path(&self, base_dir: &Path, file: &str) -> PathBuf {
+        self.response_dir.as_deref().unwrap_or(base_dir).join(file)
+    }
+}
+
+/// The state of a scenario a mock is used in and the state it moves the scenario to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Scenario {
+    pub(crate) name: String,
+    /// `None` uses the mock in any state
+    pub(crate) state: Option<String>,
+    /// `None` keeps the state
+    pub(crate) next: Option<String>,
+}
+
+/// A part of the response in the last line of a group
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Part<'a> {
+    /// a file with the content
+    File(&'a str),
+    /// the content written in the config file between double quotes
+    Text(String),
+}
+
+impl<'a> Part<'a> {
+    fn parse(part: &'a str) -> Result<Part<'a>, &'static str> {
+        let quoted = match part.strip_prefix('"') {
+            Some(quoted) => quoted,
+            None => return Ok(Part::File(part)),
+        };
+        let mut text = String::with_capacity(quoted.len());
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if chars.as_str().is_empty() => return Ok(Part::Text(text)),
+                '"' => return Err("Text after the closing quote"),
+                '\\' => text.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    _ => return Err("Unknown escape sequence in quoted text"),
+                }),
+                c => text.push(c),
+            }
+        }
+        Err("Unterminated quoted text")
+    }
+}
+
+/// Splits the last line of a group on the `;` outside of the quoted text.
+/// The parts are trimmed and come with their byte offset in the line,
+/// an unterminated text is reported with the offset of its quote.
+fn split_parts(line: &str) -> Result<Vec<(usize, &str)>, (usize, &'static str)> {
+    let part = |start: usize, end: usize| {
+        let text = &line[start..end];
+        (start + text.len() - text.trim_start().len(), text.trim())
+    };
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quote = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if quoted => escaped = true,
+            // a quote only starts a text at the beginning of a part
+            '"' if quoted || line[start..i].trim().is_empty() => {
+                quoted = !quoted;
+                quote = i;
+            },
+            ';' if !quoted => {
+                parts.push(part(start, i));
+                start = i + 1;
+            },
+            _ => (),
+        }
+    }
+    if quoted {
+        return Err((quote, "Unterminated quoted text"));
+    }
+    parts.push(part(start, line.len()));
+    Ok(parts)
+}
+
+/// The names of the profiles found in the config file and their ids
+#[derive(Debug, Clone)]
+pub(crate) struct Profiles {
+    ids: HashMap<String, isize>,
+    counter: isize,
+}
+
+impl Profiles {
+    pub fn new() -> Profiles {
+        let mut ids = HashMap::default();
+        ids.insert(String::from("default"), DEFAULT_PROFILE);
+        ids.insert(String::from("any"), ANY_PROFILE);
+        Profiles { ids, counter: DEFAULT_PROFILE }
+    }
+
+    /// Id of the profile, a new one is assigned the first time a name is seen
+    fn register(&mut self, name: &str) -> isize {
+        match self.ids.get(name) {
+            Some(id) => *id,
+            None =>  {
+                self.counter += 1;
+                self.ids.insert(String::from(name), self.counter);
+                self.counter
+            }
+        }
+    }
+
+    pub fn id(&self, name: &str) -> Option<isize> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: isize) -> Option<&str> {
+        self.ids.iter().find(|(_, i)| **i == id).map(|(name, _)| name.as_str())
+    }
+}
+
+impl Default for Profiles {
+    fn default() -> Profiles {
+        Profiles::new()
+    }
+}
+
+/**
+ * Extract the named group profile from the regex match
+ */
+fn get_named_match(group: &regex::Captures, profiles: &mut Profiles, group_name: &str, default: isize) -> isize {
+    match group.name(group_name) {
+        Some(m) => profiles.register(m.as_str()),
+        None => default
+    }
+}
+
+/// A problem found in the config file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// `None` if the config was not read from a file
+    pub file: Option<PathBuf>,
+    /// starts at 1
+    pub line: usize,
+    /// position of the offending text in the line, starts at 1
+    pub column: usize,
+    /// the offending text
+    pub text: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        write!(f, "{}:{}: {}: {}", self.line, self.column, self.message, self.text)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The problems found by the checks. The server refuses to load a config with errors,
+/// the warnings are only logged.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigReport {
+    pub errors: Vec<ConfigError>,
+    pub warnings: Vec<ConfigError>,
+}
+
+impl ConfigError {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "file": self.file.as_ref().map(|f| f.display().to_string()),
+            "line": self.line,
+            "column": self.column,
+            "text": self.text,
+            "message": self.message,
+        })
+    }
+}
+
+/// A line of a group, trimmed
+struct Line<'a> {
+    /// starts at 1
+    number: usize,
+    /// number of characters trimmed at the start
+    indent: usize,
+    text: &'a str,
+}
+
+impl<'a> Line<'a> {
+    /// An error about the text found at this byte offset of the line
+    fn error(&self, file: Option<&Path>, offset: usize, text: &str, message: String) -> ConfigError {
+        ConfigError {
+            file: file.map(Path::to_path_buf),
+            line: self.number,
+            column: self.indent + self.text[..offset].chars().count() + 1,
+            text: text.to_string(),
+            message,
+        }
+    }
+}
+
+pub fn process_config_file(config_file: &str) -> Result<Vec<Mock>, Vec<ConfigError>> {
+    parse_config(config_file, &mut Profiles::new())
+}
+
+/// Parses the config file, the profiles found are added to the ones already known.
+/// The included files are relative to the current directory.
+/// All the errors found are returned, not just the first one.
+pub(crate) fn parse_config(config_file: &str, profiles: &mut Profiles) -> Result<Vec<Mock>, Vec<ConfigError>> {
+    parse_source(config_file, None, Path::new(""), profiles)
+}
+
+/// Parses a config read from the file, if any, the includes are relative to the directory
+pub(crate) fn parse_source(
+    config_file: &str,
+    file: Option<&Path>,
+    dir: &Path,
+    profiles: &mut Profiles,
+) -> Result<Vec<Mock>, Vec<ConfigError>> {
+    // the files being read, to find the include cycles
+    let mut stack: Vec<PathBuf> = file.map(|f| fs::canonicalize(f).unwrap_or_else(|_| f.to_path_buf())).into_iter().collect();
+    let mut errors = Vec::new();
+    let config = parse_groups(config_file, file, dir, profiles, &mut stack, &mut errors);
+    if errors.is_empty() {
+        Ok(config)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parses the files matching the pattern of an include line, in alphabetical order
+fn parse_include(
+    line: &Line,
+    offset: usize,
+    file: Option<&Path>,
+    dir: &Path,
+    profiles: &mut Profiles,
+    stack: &mut Vec<PathBuf>,
+    errors: &mut Vec<ConfigError>,
+) -> Vec<Mock> {
+    let pattern = &line.text[offset..];
+    let error = |message: String| line.error(file, offset, pattern, message);
+    // only the pattern of the include is a glob, not the directory it is relative to
+    let full_pattern = Path::new(&glob::Pattern::escape(&dir.to_string_lossy())).join(pattern);
+    let paths = match glob::glob(&full_pattern.to_string_lossy()) {
+        Ok(paths) => paths,
+        Err(e) => {
+            errors.push(error(format!("Invalid include pattern, {}", e)));
+            return Vec::new();
+        }
+    };
+
+    let mut config = Vec::new();
+    let mut found = false;
+    for path in paths {
+        found = true;
+        let path = match path {
+            Ok(path) => path,
+            Err(e) => {
+                errors.push(error(format!("Could not read included file, {}", e)));
+                continue;
+            }
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                errors.push(error(format!("Could not read included file {}, {}", path.display(), e)));
+                continue;
+            }
+        };
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if stack.contains(&canonical) {
+            errors.push(error(format!("Include cycle, {} is already being read", path.display())));
+            continue;
+        }
+        stack.push(canonical);
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut mocks = parse_groups(&text, Some(&path), dir, profiles, stack, errors);
+        // the groups of the files it includes already have their directory
+        for mock in mocks.iter_mut().filter(|m| m.response_dir.is_none()) {
+            mock.response_dir = Some(dir.to_path_buf());
+        }
+        config.extend(mocks);
+        stack.pop();
+    }
+    // a pattern without wildcards names a file that has to be there
+    if !found && !pattern.contains(&['*', '?', '['][..]) {
+        errors.push(error(String::from("Included file not found")));
+    }
+    config
+}
+
+fn parse_groups(
+    config_file: &str,
+    file: Option<&Path>,
+    dir: &Path,
+    profiles: &mut Profiles,
+    stack: &mut Vec<PathBuf>,
+    errors: &mut Vec<ConfigError>,
+) -> Vec<Mock> {
+    lazy_static! {
+        static ref INCLUDE: Regex = Regex::new(r"^include\s+(?P<pattern>.+)$").unwrap();
+    }
+    let mut config = Vec::with_capacity(100);
+    for (_key, group) in config_file
+        .lines()
+        .enumerate()
+        // eliminate comments
+        .filter(|(_line_number, s)| !s.trim_start().starts_with('#'))
+        // use line is empty as criteria for grouping
+        .group_by(|(_line_number, s)| s.trim().is_empty()) 
+        // iterate over the groups
+        .into_iter()
+        // remove the groups of empty lines
+        .filter(|(key, _group)| !key)
+    {
+        let mut lines: Vec<Line> = group.map(|(line_number, s)| Line {
+            number: line_number + 1,
+            indent: s.chars().take_while(|c| c.is_whitespace()).count(),
+            text: s.trim(),
+        }).collect();
+
+        // a group made only of include lines is replaced by the mocks of the files
+        if lines.iter().all(|l| INCLUDE.is_match(l.text)) {
+            for line in &lines {
+                let offset = INCLUDE.captures(line.text).unwrap().name("pattern").unwrap().start();
+                config.extend(parse_include(line, offset, file, dir, profiles, stack, errors));
+            }
+            continue;
+        }
+
+        let errors_before = errors.len();
+        let files_line = lines.pop().unwrap();
+        let mut mock = Mock {
+            filenames: files_line.text.to_string(),
+            patterns: Vec::new(),
+            time: None,
+            timer: None,
+            delay: None,
+            profile: DEFAULT_PROFILE,
+            destination_profile: ANY_PROFILE,
+            command: Command::Serve,
+            content_length: false,
+            template: false,
+            times: None,
+            nth: None,
+            scenario: None,
+            // the group starts with the first criteria line
+            line_number: lines.first().unwrap_or(&files_line).number,
+            file: file.map(Path::to_path_buf),
+            response_dir: None,
+            id: 0,
+        };
+
+        let parts = match split_parts(files_line.text) {
+            Ok(parts) => parts,
+            Err((offset, message)) => {
+                errors.push(files_line.error(file, offset, &files_line.text[offset..], String::from(message)));
+                Vec::new()
+            }
+        };
+
+        // the leading entries starting with a ` are instructions,
+        // at most one command and any number of options
+        let mut command_found = false;
+        for (offset, instruction) in parts.iter().take_while(|(_, s)| s.starts_with('`')) {
+            lazy_static! {
+                static ref CONTENT_LENGTH: Regex = Regex::new(r"^`\s*content-length\s*$").unwrap();
+                static ref TEMPLATE: Regex = Regex::new(r"^`\s*template\s*$").unwrap();
+                static ref COUNT: Regex = Regex::new(r"^`\s*(?P<option>times|nth)\s*(?P<count>\d+)\s*$").unwrap();
+                static ref SCENARIO: Regex = Regex::new(r"^`\s*(\[[^\]]*\]\s*)?scenario\b").unwrap();
+            }
+            let result = if CONTENT_LENGTH.is_match(instruction) {
+                mock.content_length = true;
+                Ok(())
+            } else if TEMPLATE.is_match(instruction) {
+                mock.template = true;
+                Ok(())
+            } else if let Some(group) = COUNT.captures(instruction) {
+                parse_count(&group["option"], &group["count"], &mut mock)
+            } else if SCENARIO.is_match(instruction) {
+                parse_scenario(instruction, &mut mock)
+            } else if command_found {
+                // an instruction that is not a command has a better explanation
+                parse_command(instruction, &mut mock.clone(), &mut profiles.clone())
+                    .and(Err(String::from("Only one command allowed in the instructions")))
+            } else {
+                command_found = true;
+                parse_command(instruction, &mut mock, profiles)
+            };
+            if let Err(message) = result {
+                errors.push(files_line.error(file, *offset, instruction, message));
+            }
+        }
+        for (offset, part) in parts.iter().skip_while(|(_, s)| s.starts_with('`')) {
+            if let Err(message) = Part::parse(part) {
+                errors.push(files_line.error(file, *offset, part, String::from(message)));
+            }
+        }
+
+        for line in &lines {
+            match Criterion::parse(line.text) {
+                Ok(criterion) => mock.patterns.push(criterion),
+                Err(e) => errors.push(line.error(file, 0, line.text, format!("Invalid regular expression, {}", e))),
+            }
+        }
+        if errors.len() == errors_before {
+            config.push(mock);
+        }
+    }
+    config
+}
+
+/// Explains why an instruction is not understood
+fn instruction_error(instruction: &str) -> String {
+    let mut rest = instruction[1..].trim_start();
+    if rest.starts_with('[') {
+        match rest.find(']') {
+            Some(1) => return String::from("Empty profile name"),
+            Some(end) => rest = rest[end + 1..].trim_start(),
+            None => return String::from("Unterminated profile bracket"),
+        }
+    }
+    let word_end = rest.find(|c: char| !c.is_ascii_alphabetic() && c != '-').unwrap_or(rest.len());
+    let (word, argument) = (&rest[..word_end], rest[word_end..].trim());
+    // the timer follows the duration of after
+    let timer = match word {
+        "after" => argument.trim_start_matches(|c: char| c.is_ascii_digit()).trim_start(),
+        "reset" => argument,
+        _ => "",
+    };
+    match word {
+        "after" | "reset" if timer.starts_with("[]") => String::from("Empty timer name"),
+        "after" | "reset" if timer.starts_with('[') && !timer.contains(']') => String::from("Unterminated timer bracket"),
+        "after" | "delay" if argument.is_empty() => format!("Missing duration after {}", word),
+        "after" | "delay" => format!("Bad duration {}, expected a number of milliseconds", argument),
+        "profile" if !argument.starts_with('[') => String::from("Missing [profile] to switch to"),
+        "profile" if !argument.contains(']') => String::from("Unterminated profile bracket"),
+        "profile" if argument.starts_with("[]") => String::from("Empty profile name"),
+        "profile" | "reset" => format!("Unexpected text after {}: {}", word, argument),
+        "content-length" | "template" => format!("The {} option can't have a profile", word),
+        "times" | "nth" if argument.is_empty() => format!("Missing count after {}", word),
+        "times" | "nth" if argument.parse::<usize>().is_err() => format!("Bad count {}, expected a number", argument),
+        "times" | "nth" => format!("The {} option can't have a profile", word),
+        "" => String::from("Missing command"),
+        _ => format!("Unknown command {}", word),
+    }
+}
+
+/// Sets the `times` or `nth` option of the mock
+fn parse_count(option: &str, count: &str, mock: &mut Mock) -> Result<(), String> {
+    let count = match count.parse::<usize>() {
+        Ok(0) => return Err(format!("The count of {} must be at least 1", option)),
+        Ok(count) => count,
+        Err(_) => return Err(format!("Bad count {}, expected a number", count)),
+    };
+    if mock.times.is_some() || mock.nth.is_some() {
+        return Err(String::from("Only one of times and nth allowed"));
+    }
+    if option == "times" {
+        mock.times = Some(count);
+    } else {
+        mock.nth = Some(count);
+    }
+    Ok(())
+}
+
+/// Parses a `scenario name [state] -> [next state]` option, either state can be left out
+fn parse_scenario(instruction: &str, mock: &mut Mock) -> Result<(), String> {
+    lazy_static! {
+        static ref SCENARIO: Regex = Regex::new(r"(?x)
+            ^`\s*scenario\s+(?P<name>[^\[\s]+)\s*  # scenario name
+            (\[(?P<state>[^\]]*)\]\s*)?             # state the mock is used in
+            (->\s*\[(?P<next>[^\]]*)\]\s*)?$        # state to move to
+            ").unwrap();
+    }
+    if instruction[1..].trim_start().starts_with('[') {
+        return Err(String::from("The scenario option can't have a profile"));
+    }
+    let group = SCENARIO.captures(instruction)
+        .ok_or_else(|| String::from("Expected scenario name [state] -> [next state]"))?;
+    let state = |name| group.name(name).map(|s| s.as_str().trim().to_string());
+    let (state, next) = (state("state"), state("next"));
+    if state.is_none() && next.is_none() {
+        return Err(String::from("Missing [state] or -> [state] after the scenario name"));
+    }
+    if state.iter().chain(next.iter()).any(String::is_empty) {
+        return Err(String::from("Empty state name"));
+    }
+    if mock.scenario.is_some() {
+        return Err(String::from("Only one scenario allowed in the instructions"));
+    }
+    mock.scenario = Some(Scenario { name: group["name"].to_string(), state, next });
+    Ok(())
+}
+
+/// Parses an instruction with a command like `after` or `reset`, optionally with a profile
+fn parse_command(instruction: &str, mock: &mut Mock, profiles: &mut Profiles) -> Result<(), String> {
+    let duration = |text: &str| text.parse().map(Duration::from_millis)
+        .map_err(|_| format!("Bad duration {}, expected a number of milliseconds", text));
+    let mut get_profile = |group| get_named_match(group, profiles, "profile", DEFAULT_PROFILE);
+    {
+        // after
+        lazy_static! {
+            static ref TIME: Regex = Regex::new(r"(?x)
+                ^`\s*(\[(?P<profile>.+)\]\s+)? # profile name
+                after\s*(?P<time>\d+)\s*     # after duration
+                (\[(?P<timer>[^\]]+)\]\s*)?$  # timer name
+                ").unwrap();
+        }
+        if let Some(group) = TIME.captures(instruction) {
+            mock.time = Some(duration(&group["time"])?);
+            mock.timer = group.name("timer").map(|t| t.as_str().trim().to_string());
+            mock.profile = get_profile(&group);
+            mock.command = Command::After;
+            return Ok(());
+        }
+    }
+    {
+        // delay
+        lazy_static! {
+            static ref DELAY: Regex = Regex::new(r"(?x)
+                ^`\s*(\[(?P<profile>.+)\]\s+)? # profile name
+                delay\s*(?P<delay>\d+)\s*$      # delay duration
+                ").unwrap();
+        }
+        if let Some(group) = DELAY.captures(instruction) {
+            mock.delay = Some(duration(&group["delay"])?);
+            mock.profile = get_profile(&group);
+            mock.command = Command::Delay;
+            return Ok(());
+        }
+    }
+    {
+        // profile
+        lazy_static! {
+            static ref SWITCH_PROFILE: Regex = Regex::new(r"(?x)
+                ^`\s*(\[(?P<profile_src>.+)\]\s+)? # profile name
+                profile\s+\[(?P<profile_dest>.+)\]\s*$ # profile to swith to
+                ").unwrap();
+        }
+        if let Some(group) = SWITCH_PROFILE.captures(instruction) {
+            mock.profile = get_named_match(&group, profiles, "profile_src", DEFAULT_PROFILE);
+            mock.destination_profile = get_named_match(&group, profiles, "profile_dest", ANY_PROFILE);
+            mock.command = Command::Profile;
+            return Ok(());
+        }
+    }
+    {
+        // only profile specified
+        lazy_static! {
+            static ref PROFILE: Regex = Regex::new(r"^`\s*\[(?P<profile>.+)\]\s*$").unwrap();
+        }
+        if let Some(group) = PROFILE.captures(instruction) {
+            mock.profile = get_profile(&group);
+            return Ok(());
+        }
+    }
+    {
+        // reset
+        lazy_static! {
+            static ref RESET: Regex = Regex::new(r"(?x)
+                ^`\s*(\[(?P<profile>.+)\]\s+)? # profile name
+                reset\s*(\[(?P<timer>[^\]]+)\]\s*)?$ # timer name
+                ").unwrap();
+        }
+        if let Some(group) = RESET.captures(instruction) {
+            mock.profile = get_profile(&group);
+            mock.timer = group.name("timer").map(|t| t.as_str().trim().to_string());
+            mock.command = Command::Reset;
+            return Ok(());
+        }
+    }
+    Err(instruction_error(instruction))
+}
+
+/// Checks the files of the responses exist, relative paths are resolved against the base directory
+pub fn verify_response_files_exist(config: &[Mock], base_dir: &Path) -> bool {
+    info!("Verifying all referenced files exist");
+    log_errors(find_missing_response_files(config, base_dir))
+}
+
+/// The response files that don't exist, each reported once
+pub fn find_missing_response_files(config: &[Mock], base_dir: &Path) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let mut verified_files: HashSet<PathBuf> = HashSet::default();
+    for mock in config {
+        for file in mock.response_files() {
+            let path = mock.response_path(base_dir, file);
+            if !verified_files.contains(&path) && !path.exists() {
+                errors.push(mock.error(file, String::from("Could not find response file")));
+                info!("{:#?}", mock);
+            }
+            verified_files.insert(path);
+        }
+    }
+    errors
+}
+
+/// Logs the errors, returns true if there are none
+fn log_errors(errors: Vec<ConfigError>) -> bool {
+    for e in &errors {
+        error!("{}", e);
+    }
+    errors.is_empty()
+}
+
+/// Parses the config file and runs all the checks on it, returns all the problems found.
+/// The response files are relative to the directory of the config file.
+pub fn check_config_file(config_file: &Path) -> io::Result<ConfigReport> {
+    let text = fs::read_to_string(config_file)?;
+    let dir = config_file.parent().unwrap_or_else(|| Path::new(""));
+    let config = match parse_source(&text, Some(config_file), dir, &mut Profiles::new()) {
+        Ok(config) => config,
+        // the checks would report the mocks that could not be parsed
+        Err(errors) => return Ok(ConfigReport { errors, warnings: Vec::new() }),
+    };
+    Ok(ConfigReport { errors: find_errors(&config, dir), warnings: find_warnings(&config) })
+}
+
+/// The problems that make the config unusable, the server refuses to load it if there is any:
+/// missing response files, profiles never switched to, shadowed mocks and unreachable states
+pub(crate) fn find_errors(config: &[Mock], base_dir: &Path) -> Vec<ConfigError> {
+    let mut errors = find_missing_response_files(config, base_dir);
+    errors.extend(find_unreferenced_profiles(config));
+    errors.extend(find_shadowed_mocks(config));
+    errors.extend(find_unreachable_states(config));
+    errors
+}
+
+/// The problems that are likely mistakes but leave every mock usable:
+/// timers never reset and dead end states
+pub(crate) fn find_warnings(config: &[Mock]) -> Vec<ConfigError> {
+    let mut warnings = find_timers_never_reset(config);
+    warnings.extend(find_dead_end_states(config));
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn process_config_file() {
+        let config_file = r##"
+            # comment
+            POST /path
+            headers;body
+
+            GET /path
+            headers
+            "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert_eq!(2, config.len());
+        assert_eq!(super::DEFAULT_PROFILE, config[0].profile);
+    }
+
+    #[test]
+    fn process_config_file_with_time() {
+        let config_file = r##"
+        POST /path1
+        `after 1000;headers;body
+
+        POST /path2
+        `delay 2000;headers;body
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert_eq!(2, config.len());
+        assert_eq!(Some(super::Duration::from_millis(1000)), config.first().unwrap().time);
+        assert_eq!(Some(super::Duration::from_millis(2000)), config.last().unwrap().delay);
+    }
+
+    #[test]
+    fn process_bad_config_file() {
+        let config_file = r##"
+        POST /path
+        `delay; headers
+        "##;
+
+        let config = super::process_config_file(config_file);
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn process_bad_config_file2() {
+        let config_file = r##"
+        POST /path
+        `1000; headers
+        "##;
+
+        let config = super::process_config_file(config_file);
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn process_config_file_with_content_length() {
+        let config_file = r##"
+        GET /icon
+        `content-length;headers;icon
+
+        GET /path
+        `[my profile] delay 100;`content-length;headers;body
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert!(config[0].content_length);
+        assert_eq!(vec!["headers", "icon"], config[0].response_files().collect::<Vec<_>>());
+        assert!(config[1].content_length);
+        assert_eq!(Some(super::Duration::from_millis(100)), config[1].delay);
+        assert_eq!(1, config[1].profile);
+        assert_eq!(vec!["GET /path"], config[1].criteria());
+        assert_eq!(vec!["headers", "body"], config[1].files());
+        assert_eq!((5, None), (config[1].line(), config[1].file()));
+    }
+
+    #[test]
+    fn process_config_file_with_template() {
+        let config = super::process_config_file("~^GET /orders/(?P<id>\\d+)\n`template;`content-length;headers;order\n\n/a\nheaders\n").unwrap();
+        assert!(config[0].template);
+        assert!(config[0].content_length);
+        assert!(!config[1].template);
+        assert_eq!(vec!["The template option can't have a profile"], messages("/a\n`[p] template;headers\n"));
+    }
+
+    #[test]
+    fn process_config_file_with_text() {
+        let config_file = r##"
+        GET /ok
+        `content-length;headers;"{\"ok\":true;\"path\":\"a\\b\"}\n";footer
+
+        GET /inline
+        "HTTP/1.1 204 No Content\r\n\r\n"
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert_eq!(
+            vec![
+                super::Part::File("headers"),
+                super::Part::Text(String::from("{\"ok\":true;\"path\":\"a\\b\"}\n")),
+                super::Part::File("footer"),
+            ],
+            config[0].response_parts()
+        );
+        assert_eq!(vec!["headers", "footer"], config[0].response_files().collect::<Vec<_>>());
+        assert_eq!(vec![super::Part::Text(String::from("HTTP/1.1 204 No Content\r\n\r\n"))], config[1].response_parts());
+
+        assert_eq!(vec![(0, "a\"b"), (5, "c")], super::split_parts("a\"b; c").unwrap());
+        assert_eq!(vec!["Unterminated quoted text"], messages("/path\nheaders;\"open;body\n"));
+        assert_eq!(vec!["Text after the closing quote"], messages("/path\n\"a\"b\n"));
+        assert_eq!(vec!["Unknown escape sequence in quoted text"], messages("/path\n\"a\\x\"\n"));
+    }
+
+    #[test]
+    fn include_files() {
+        // the brackets are not a glob pattern
+        let dir = crate::test_dir::TestDir::new("include-[x]");
+        std::fs::create_dir(dir.join("parts")).unwrap();
+        std::fs::write(dir.join("parts").join("b.txt"), "/b\nheaders;b\n").unwrap();
+        std::fs::write(dir.join("parts").join("a.txt"), "# first\n/a\nheaders;a\n\ninclude ../common.txt\n").unwrap();
+        std::fs::write(dir.join("common.txt"), "/common\nheaders;common\n").unwrap();
+        let main = dir.join("config.txt");
+        let config = "/first\nheaders;first\n\ninclude parts/*.txt\ninclude missing/*.txt\n\n/last\nheaders;last\n";
+
+        let mut profiles = super::Profiles::new();
+        let mocks = super::parse_source(config, Some(&main), &dir, &mut profiles).unwrap();
+        let criteria: Vec<String> = mocks.iter().map(|m| m.patterns[0].to_string()).collect();
+        assert_eq!(vec!["/first", "/a", "/common", "/b", "/last"], criteria);
+        assert_eq!(Some(dir.join("parts").join("a.txt")), mocks[1].file);
+        assert_eq!(2, mocks[1].line_number);
+        assert_eq!(Some(main.clone()), mocks[4].file);
+        // the response files are relative to the file the group is in
+        assert_eq!(dir.join("parts").join("a"), mocks[1].response_path(&dir, "a"));
+        assert_eq!(dir.join("parts/../common"), mocks[2].response_path(&dir, "common"));
+        assert_eq!(dir.join("last"), mocks[4].response_path(&dir, "last"));
+        for file in ["first", "headers", "common", "last", "parts/headers", "parts/a", "parts/b"] {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        assert!(super::find_missing_response_files(&mocks, &dir).is_empty());
+        std::fs::remove_file(dir.join("parts/a")).unwrap();
+        assert_eq!(1, super::find_missing_response_files(&mocks, &dir).len());
+
+        let errors = super::parse_source("\n  include parts/none.txt\n", Some(&main), &dir, &mut profiles).unwrap_err();
+        assert_eq!("Included file not found", errors[0].message);
+        assert_eq!((Some(main.clone()), 2, 11), (errors[0].file.clone(), errors[0].line, errors[0].column));
+
+        std::fs::write(dir.join("common.txt"), "include parts/a.txt\n").unwrap();
+        let errors = super::parse_source(config, Some(&main), &dir, &mut profiles).unwrap_err();
+        assert!(errors[0].message.starts_with("Include cycle"), "{}", errors[0]);
+        assert!(errors[0].file.as_ref().unwrap().ends_with("common.txt"));
+        assert_eq!(1, errors.len());
+    }
+
+    pub(super) fn messages(config_file: &str) -> Vec<String> {
+        super::process_config_file(config_file).unwrap_err().into_iter().map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn errors_with_location() {
+        let config_file = "/a\n`after abc;headers\n\n# comment\n/b\n  `[p;body\n\n~(\nheaders\n\n/d\n`reset;`[x] content-length;`delay 5;headers\n";
+        let errors = super::process_config_file(config_file).unwrap_err();
+        let found: Vec<(usize, usize, &str, &str)> = errors.iter().map(|e| (e.line, e.column, e.text.as_str(), e.message.as_str())).collect();
+        assert_eq!((2, 1, "`after abc", "Bad duration abc, expected a number of milliseconds"), found[0]);
+        assert_eq!((6, 3, "`[p", "Unterminated profile bracket"), found[1]);
+        assert_eq!((8, 1, "~("), (found[2].0, found[2].1, found[2].2));
+        assert!(found[2].3.starts_with("Invalid regular expression"));
+        assert_eq!((12, 8, "`[x] content-length", "The content-length option can't have a profile"), found[3]);
+        assert_eq!((12, 28, "`delay 5", "Only one command allowed in the instructions"), found[4]);
+        assert_eq!(5, errors.len());
+        assert_eq!("2:1: Bad duration abc, expected a number of milliseconds: `after abc", errors[0].to_string());
+
+        assert_eq!(vec!["Unknown command deploy"], messages("/a\n`deploy 5;headers\n"));
+        assert_eq!(vec!["Missing duration after delay"], messages("/a\n`[p] delay;headers\n"));
+        assert_eq!(vec!["Bad duration 99999999999999999999999, expected a number of milliseconds"], messages("/a\n`after 99999999999999999999999;headers\n"));
+        assert_eq!(vec!["Missing [profile] to switch to"], messages("/a\n`profile;headers\n"));
+        assert_eq!(vec!["Unterminated profile bracket"], messages("/a\n`profile [p;headers\n"));
+        assert_eq!(vec!["Empty profile name"], messages("/a\n`[] reset;headers\n"));
+    }
+
+    #[test]
+    fn check_config_file() {
+        let dir = crate::test_dir::TestDir::new("check");
+        std::fs::write(dir.join("headers"), "HTTP/1.1 200 OK\n\n").unwrap();
+        let config_file = dir.join("config.txt");
+        std::fs::write(&config_file, "/a\nheaders;missing\n\n/ab\nheaders\n\n/p\n`[p];headers\n").unwrap();
+        let errors = super::check_config_file(&config_file).unwrap().errors;
+        let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!("Could not find response file", messages[0]);
+        assert_eq!("Non default profile not referenced by any profile switch statement", messages[1]);
+        assert!(messages[2].starts_with("Shadowed by the mock at"));
+        assert_eq!(4, errors[2].line);
+        assert_eq!(3, errors.len());
+
+        std::fs::write(&config_file, "/a\nheaders\n").unwrap();
+        assert_eq!(super::ConfigReport::default(), super::check_config_file(&config_file).unwrap());
+
+        // warnings don't make the config invalid
+        std::fs::write(&config_file, "/a\n`after 100 [t];headers\n").unwrap();
+        let report = super::check_config_file(&config_file).unwrap();
+        assert!(report.errors.is_empty());
+        assert_eq!("Timer [t] not reset by any reset statement", report.warnings[0].message);
+        assert!(super::check_config_file(&dir.join("none.txt")).is_err());
+    }
+
+    #[test]
+    fn process_bad_config_file_two_commands() {
+        let config_file = r##"
+        POST /path
+        `reset;`delay 100;headers
+        "##;
+
+        assert!(super::process_config_file(config_file).is_err());
+    }
+
+    #[test]
+    fn process_config_file_with_regex() {
+        let config_file = r##"
+        ~^GET /orders/\d+ HTTP
+        headers;order
+
+        ~^GET /orders/(
+        headers;order
+        "##;
+
+        assert!(super::process_config_file(config_file).is_err());
+        let config = super::process_config_file(&config_file[..config_file.find("~^GET /orders/(").unwrap()]).unwrap();
+        assert!(config[0].patterns[0].is_met("POST /x HTTP/1.1\r\n\r\nGET /orders/12 HTTP/1.1".into()));
+        assert!(!config[0].patterns[0].is_met("GET /orders/ HTTP/1.1\r\n".into()));
+    }
+
+    #[test]
+    fn process_config_file_with_negation() {
+        let config_file = r##"
+        GET /orders
+        !Authorization:
+        headers;unauthorized
+
+        GET /orders
+        !~^X-Tenant: \d+
+        headers;orders
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert!(config[0].patterns[1].is_met("GET /orders HTTP/1.1\r\n\r\n".into()));
+        assert!(!config[0].patterns[1].is_met("GET /orders HTTP/1.1\r\nAuthorization: Basic\r\n\r\n".into()));
+        assert!(config[1].patterns[1].is_met("GET /orders HTTP/1.1\r\nX-Tenant: abc\r\n\r\n".into()));
+        assert!(!config[1].patterns[1].is_met("GET /orders HTTP/1.1\r\nX-Tenant: 12\r\n\r\n".into()));
+    }
+
+    #[test]
+    fn process_config_file_with_wire_criteria() {
+        let config = super::process_config_file("POST /upload\n@~^5\\s*$\n!@hello\nheaders\n").unwrap();
+        let request = super::RequestText {
+            decoded: "POST /upload HTTP/1.1\r\n\r\nhello",
+            wire: "POST /upload HTTP/1.1\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        };
+        assert!(config[0].patterns[1].is_met(request));
+        assert!(!config[0].patterns[1].is_met(request.decoded.into()));
+        assert!(!config[0].patterns[2].is_met(request));
+        assert_eq!("!@hello", config[0].patterns[2].to_string());
+        assert!(!super::verify_mocks_dont_shadow_each_other(&super::process_config_file("/a\n@~^5$\nheaders\n\n/a\n@~^5$\nheaders\n").unwrap()));
+    }
+
+    #[test]
+    fn process_config_file_with_captures() {
+        let config_file = r##"
+        ~^GET /orders/(?P<id>\d+)(?P<version>/v\d)? HTTP
+        headers;order
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        let mut captures = super::HashMap::new();
+        config[0].patterns[0].captures("GET /orders/42 HTTP/1.1\r\n\r\n".into(), &mut captures);
+        assert_eq!(Some(&String::from("42")), captures.get("id"));
+        assert!(!captures.contains_key("version"));
+    }
+
+    #[test]
+    fn parse_config_with_known_profiles() {
+        let mut profiles = super::Profiles::new();
+        super::parse_config("/a\n`[first];headers\n", &mut profiles).unwrap();
+        let config = super::parse_config("/b\n`[second] profile [first];headers\n", &mut profiles).unwrap();
+        assert_eq!(Some(1), profiles.id("first"));
+        assert_eq!(Some("second"), profiles.name(2));
+        assert_eq!(1, config[0].destination_profile);
+        assert_eq!("/b", config[0].patterns[0].to_string());
+    }
+
+    #[test]
+    fn process_config_with_profile_name() {
+        let config_file = r##"
+        POST /path1
+        `[my profile] after 1000;headers;body
+
+        GET /path
+        `[second profile];headers;body
+
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert_eq!(2, config.len());
+        assert_eq!(Some(super::Duration::from_millis(1000)), config.first().unwrap().time);
+        assert_eq!(1, config.first().unwrap().profile);
+        assert_eq!(2, config.last().unwrap().profile);
+    }
+}
+
+pub fn verify_all_profiles_are_referenced(config: &[Mock]) -> bool {
+    info!("Verifying all profiles are referenced");
+    log_errors(find_unreferenced_profiles(config))
+}
+
+/// The mocks of a profile no mock switches to
+pub fn find_unreferenced_profiles(config: &[Mock]) -> Vec<ConfigError> {
+    let referenced_profiles: HashSet<isize> = config.iter().filter(|m| m.command == Command::Profile).map(|m| m.destination_profile).collect();
+    config.iter()
+        .filter(|m| m.profile != DEFAULT_PROFILE && m.profile != ANY_PROFILE)
+        .filter(|m| !referenced_profiles.contains(&m.profile))
+        .map(|m| m.error(&m.filenames, String::from("Non default profile not referenced by any profile switch statement")))
+        .collect()
+}
+
+#[cfg(test)]
+    mod tests_profiles_referenced {
+    
+    #[test]
+    fn process_bad_config_file_with_unreferenced_profile() {
+        let config_file = r##"
+        POST /path
+        `[profile1]; headers
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert!(!super::verify_all_profiles_are_referenced(&config));
+    }
+
+    #[test]
+    fn process_config_file_with_unreferenced_default_profile() {
+        let config_file = r##"
+        GET /default
+        headers;default.html
+        
+        POST /path
+        `[profile1]; headers
+
+        /switch
+        `profile [profile1]; headers; ok.html
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert!(super::verify_all_profiles_are_referenced(&config));
+    }
+
+    #[test]
+    fn process_config_file_with_profiled_switch_profile() {
+        let config_file = r##"
+        GET /default
+        headers;default.html
+        
+        POST /path
+        `[profile1]; headers
+
+        /switch
+        `[default] profile [profile1]; headers; ok.html
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert!(super::verify_all_profiles_are_referenced(&config));
+    }
+
+    #[test]
+    fn process_config_with_reset() {
+        let config_file = r##"
+
+        POST /path
+        `[default] profile [profile]; headers
+
+        /switch/reset
+        `[profile] reset; headers
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert_eq!(1, config[1].profile);
+        assert!(super::verify_all_profiles_are_referenced(&config));
+    }
+}
+
+/// True if every request the tail matches is answered by the head first
+pub(crate) fn shadowed(head: &Mock, tail: &Mock) -> bool {
+    if head.profile != ANY_PROFILE && head.profile != tail.profile {
+        return false;
+    }
+    // the timer only matters to the after mocks
+    if head.time != tail.time || (head.time.is_some() && head.timer != tail.timer) {
+        return false;
+    }
+    // the requests the head lets through can reach the tail
+    if head.times.is_some() || head.nth.is_some() {
+        return false;
+    }
+    // in the other states of the scenario the tail is reached
+    if let Some(Scenario { name, state: Some(state), .. }) = &head.scenario {
+        match &tail.scenario {
+            Some(Scenario { name: tail_name, state: Some(tail_state), .. }) if tail_name == name && tail_state == state => (),
+            _ => return false,
+        }
+    }
+    
+    head.patterns.iter().all(|hp|
+        // a head pattern not implied by any tail pattern -> tail mock is not shadowed
+        tail.patterns.iter().any(|pt| hp.implied_by(pt))
+    )
+}
+
+pub fn verify_mocks_dont_shadow_each_other(config: &[Mock]) -> bool {
+    info!("Verifying mocks don't shadow each other, i.e. all mocks are reachable");
+    log_errors(find_shadowed_mocks(config))
+}
+
+/// The mocks that can't be reached because an earlier one matches all their requests
+pub fn find_shadowed_mocks(config: &[Mock]) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let mut remaining: &[Mock]  = config;
+
+    let mut safety_count = 0;
+    while remaining.len() > 1 {
+        safety_count += 1;
+        if safety_count > 100000 {
+            println!("Remaining to check for shadow {:#?}", remaining);
+            panic!("Too many iterations in verify_mocks_dont_shadow_each_other");
+        }
+        if let Some((head, tail)) = remaining.split_first() {
+            tail.iter().filter(|t| shadowed(head, t)).for_each(|t| {
+                let criteria = t.patterns.iter().map(|p| p.to_string()).join(", ");
+                errors.push(t.error(&criteria, format!("Shadowed by the mock at {}, maybe they are in the wrong order?", head.location())));
+                debug!("Criteria {:#?} shadows {:#?}, maybe they are in the wrong order?", head, t)
+            });
+            remaining = tail;
+        }
+    }
+    errors
+}
+
+/// The `after` mocks on a named timer no mock resets, the default timer starts with the server
+pub fn find_timers_never_reset(config: &[Mock]) -> Vec<ConfigError> {
+    let reset_timers: HashSet<&str> = config.iter()
+        .filter(|m| m.command == Command::Reset)
+        .filter_map(|m| m.timer.as_deref())
+        .collect();
+    config.iter()
+        .filter(|m| m.command == Command::After)
+        .filter_map(|m| m.timer.as_deref().map(|timer| (m, timer)))
+        .filter(|(_, timer)| !reset_timers.contains(timer))
+        .map(|(m, timer)| m.error(&m.filenames, format!("Timer [{}] not reset by any reset statement", timer)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests_timers {
+    use super::tests::messages;
+
+    #[test]
+    fn named_timers() {
+        let config_file = r##"
+        /payment/start
+        `reset [payment];headers
+
+        /payment/status
+        `after 5000 [payment];headers;done
+
+        /export/status
+        `[p] after 100 [ export ];headers;done
+
+        /payment/status
+        `after 5000;headers;done
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert_eq!(Some("payment"), config[0].timer.as_deref());
+        assert_eq!(Some("payment"), config[1].timer.as_deref());
+        assert_eq!(Some(super::Duration::from_millis(5000)), config[1].time);
+        assert_eq!(Some("export"), config[2].timer.as_deref());
+        assert_eq!(None, config[3].timer);
+        // the same criteria on another timer are not shadowed
+        assert!(super::find_shadowed_mocks(&config).is_empty());
+
+        let errors = super::find_timers_never_reset(&config);
+        assert_eq!(1, errors.len());
+        assert_eq!("Timer [export] not reset by any reset statement", errors[0].message);
+        assert_eq!(8, errors[0].line);
+
+        let config = super::process_config_file("/pay/start\n`reset [payment];headers\n\n/pay/start\nheaders\n").unwrap();
+        assert_eq!(1, super::find_shadowed_mocks(&config).len());
+    }
+
+    #[test]
+    fn timer_errors() {
+        assert_eq!(vec!["Empty timer name"], messages("/a\n`reset [];headers\n"));
+        assert_eq!(vec!["Unterminated timer bracket"], messages("/a\n`after 100 [payment;headers\n"));
+        assert_eq!(vec!["Unexpected text after reset: payment"], messages("/a\n`reset payment;headers\n"));
+    }
+}
+
+/// The mocks of each scenario, in the order the scenarios first appear
+fn scenarios(config: &[Mock]) -> Vec<(&str, Vec<&Mock>)> {
+    let mut scenarios: Vec<(&str, Vec<&Mock>)> = Vec::new();
+    for mock in config {
+        if let Some(scenario) = &mock.scenario {
+            match scenarios.iter_mut().find(|(name, _)| *name == scenario.name) {
+                Some((_, mocks)) => mocks.push(mock),
+                None => scenarios.push((&scenario.name, vec![mock])),
+            }
+        }
+    }
+    scenarios
+}
+
+/// The states the mocks of a scenario can move it to from the initial one
+fn reachable_states<'a>(mocks: &[&'a Mock]) -> HashSet<&'a str> {
+    let mut reachable: HashSet<&str> = HashSet::new();
+    reachable.insert(INITIAL_STATE);
+    loop {
+        let count = reachable.len();
+        for scenario in mocks.iter().filter_map(|m| m.scenario.as_ref()) {
+            if let Some(next) = &scenario.next {
+                if scenario.state.as_deref().is_none_or(|s| reachable.contains(s)) {
+                    reachable.insert(next);
+                }
+            }
+        }
+        if reachable.len() == count {
+            return reachable;
+        }
+    }
+}
+
+/// The states of the scenarios no sequence of transitions leads to from the initial one,
+/// each reported once
+pub fn find_unreachable_states(config: &[Mock]) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    for (name, mocks) in scenarios(config) {
+        let reachable = reachable_states(&mocks);
+        let mut reported = HashSet::new();
+        for mock in &mocks {
+            let scenario = mock.scenario.as_ref().unwrap();
+            for state in scenario.state.iter().chain(scenario.next.iter()) {
+                if !reachable.contains(state.as_str()) && reported.insert(state) {
+                    errors.push(mock.error(&mock.filenames, format!("State [{}] of scenario {} not reachable from [{}]", state, name, INITIAL_STATE)));
+                }
+            }
+        }
+    }
+    errors
+}
+
+/// The states of the scenarios no mock of the scenario is used in,
+/// once there the scenario is stuck. Reported at the mock moving to them.
+pub fn find_dead_end_states(config: &[Mock]) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    for (name, mocks) in scenarios(config) {
+        let used_in = |state: &str| mocks.iter()
+            .filter_map(|m| m.scenario.as_ref())
+            .any(|s| s.state.as_deref().is_none_or(|s| s == state));
+        if !used_in(INITIAL_STATE) {
+            let mock = mocks[0];
+            errors.push(mock.error(&mock.filenames, format!("State [{}] of scenario {} is a dead end, no mock is used in it", INITIAL_STATE, name)));
+        }
+        let mut reported = HashSet::new();
+        for mock in &mocks {
+            if let Some(next) = &mock.scenario.as_ref().unwrap().next {
+                if next != INITIAL_STATE && !used_in(next) && reported.insert(next) {
+                    errors.push(mock.error(&mock.filenames, format!("State [{}] of scenario {} is a dead end, no mock is used in it", next, name)));
+                }
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests_scenarios {
+    use super::tests::messages;
+
+    #[test]
+    fn parse_scenario() {
+        let config_file = r##"
+        POST /cart
+        `scenario checkout -> [cart];headers;cart
+
+        POST /pay
+        `scenario checkout [cart] -> [paid];`times 1;headers;paid
+
+        GET /order
+        `scenario checkout [ paid ];headers;order
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        let scenario = |state: Option<&str>, next: Option<&str>| Some(super::Scenario {
+            name: String::from("checkout"),
+            state: state.map(String::from),
+            next: next.map(String::from),
+        });
+        assert_eq!(scenario(None, Some("cart")), config[0].scenario);
+        assert_eq!(scenario(Some("cart"), Some("paid")), config[1].scenario);
+        assert_eq!(Some(1), config[1].times);
+        assert_eq!(scenario(Some("paid"), None), config[2].scenario);
+        assert!(super::find_unreachable_states(&config).is_empty());
+        assert!(super::find_dead_end_states(&config).is_empty());
+
+        assert_eq!(vec!["Missing [state] or -> [state] after the scenario name"], messages("/a\n`scenario checkout;headers\n"));
+        assert_eq!(vec!["Empty state name"], messages("/a\n`scenario checkout [] -> [a];headers\n"));
+        assert_eq!(vec!["Expected scenario name [state] -> [next state]"], messages("/a\n`scenario checkout -> paid;headers\n"));
+        assert_eq!(vec!["The scenario option can't have a profile"], messages("/a\n`[p] scenario checkout [a];headers\n"));
+        assert_eq!(vec!["Only one scenario allowed in the instructions"], messages("/a\n`scenario a [x];`scenario b [y];headers\n"));
+    }
+
+    #[test]
+    fn states_graph() {
+        let config_file = r##"
+        /cart
+        `scenario checkout [start] -> [cart];headers
+
+        /pay
+        `scenario checkout [cart] -> [paid];headers
+
+        /refund
+        `scenario checkout [refunded] -> [start];headers
+
+        /export
+        `scenario export [start] -> [running];headers
+
+        /export/status
+        `scenario export [running] -> [done];headers
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        let unreachable = super::find_unreachable_states(&config);
+        assert_eq!(vec!["State [refunded] of scenario checkout not reachable from [start]"], unreachable.iter().map(|e| e.message.as_str()).collect::<Vec<_>>());
+        assert_eq!(8, unreachable[0].line);
+
+        let dead_ends: Vec<(usize, String)> = super::find_dead_end_states(&config).into_iter().map(|e| (e.line, e.message)).collect();
+        assert_eq!(vec![
+            (5, String::from("State [paid] of scenario checkout is a dead end, no mock is used in it")),
+            (14, String::from("State [done] of scenario export is a dead end, no mock is used in it")),
+        ], dead_ends);
+        // the mocks used in different states don't shadow each other
+        assert!(super::find_shadowed_mocks(&config).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests_counts {
+    use super::tests::messages;
+
+    #[test]
+    fn times_and_nth() {
+        let config_file = r##"
+        POST /payments
+        `times 2;headers;unavailable
+
+        POST /payments
+        `nth 3;`[p] after 100;`content-length;headers;slow
+
+        POST /payments
+        `profile [p];headers;ok
+        "##;
+
+        let config = super::process_config_file(config_file).unwrap();
+        assert_eq!(Some(2), config[0].times);
+        assert_eq!(Some(3), config[1].nth);
+        assert!(config[1].content_length);
+        assert_eq!(super::Command::After, config[1].command);
+        assert!(super::find_shadowed_mocks(&config).is_empty());
+    }
+
+    #[test]
+    fn count_errors() {
+        assert_eq!(vec!["The count of times must be at least 1"], messages("/a\n`times 0;headers\n"));
+        assert_eq!(vec!["Missing count after nth"], messages("/a\n`nth;headers\n"));
+        assert_eq!(vec!["Bad count x, expected a number"], messages("/a\n`times x;headers\n"));
+        assert_eq!(vec!["The times option can't have a profile"], messages("/a\n`[p] times 2;headers\n"));
+        assert_eq!(vec!["Only one of times and nth allowed"], messages("/a\n`times 2;`nth 1;headers\n"));
+    }
+}
+
+#[cfg(test)]
+mod tests_shadowing {
+    use std::time::{Duration};
+    fn make_mock(patterns: Vec<&str>, profile: isize, time: Option<Duration>) -> super::Mock {
+        super::Mock {
+            filenames: String::new(),
+            patterns: patterns.into_iter().map(|p| super::Criterion::parse(p).unwrap()).collect(),
+            time,
+            timer: None,
+            delay: None,
+            profile,
+            destination_profile: -1,
+            command: super::Command::Serve,
+            content_length: false,
+            template: false,
+            times: None,
+            nth: None,
+            scenario: None,
+            line_number: 0,
+            file: None,
+            response_dir: None,
+            id: 0,
+        }
+    }
+
+    #[test]
+    fn shadowed_test() {
+        let head = make_mock(vec!["switch"], 0, None);
+        let tail = make_mock(vec!["switch_to_default"], 0, None);
+        assert!(super::shadowed(&head, &tail));
+    }
+
+    #[test]
+    fn not_shadowed_test() {
+        let head = make_mock(vec!["switch_to_default"], 0, None);
+        let tail = make_mock(vec!["switch"], 0, None);
+        assert!(!super::shadowed(&head, &tail));
+    }    
+    #[test]
+    fn multiple_not_shadowed_test() {
+        let head = make_mock(vec!["switch", "header:value"], 0, None);
+        let tail = make_mock(vec!["switch_to_default"], 0, None);
+        assert!(!super::shadowed(&head, &tail));
+    }
+
+    #[test]
+    fn multiple_not_shadowed_test_more_lines() {
+        let head = make_mock(vec!["switch", "header:value"], 0, None);
+        let tail = make_mock(vec!["switch_to_default"], 0, None);
+        assert!(!super::shadowed(&head, &tail));
+    }
+
+    #[test]
+    fn not_shadowd_if_different_profile() {
+        let head = make_mock(vec!["switch"], 0, None);
+        let tail = make_mock(vec!["switch"], 1, None);
+        assert!(!super::shadowed(&head, &tail));
+
+        let head = make_mock(vec!["switch"], -1, None);
+        let tail = make_mock(vec!["switch"], 1, None);
+        assert!(super::shadowed(&head, &tail));        
+    }
+    #[test]
+    fn not_shadowd_if_different_time() {
+        let head = make_mock(vec!["switch"], 0, Some(Duration::from_millis(1000)));
+        let tail = make_mock(vec!["switch"], 0, Some(Duration::from_millis(0)));
+        assert!(!super::shadowed(&head, &tail));
+    }
+
+    #[test]
+    fn not_shadowed_if_only_one_has_time() {
+        let head = make_mock(vec!["switch"], 0, Some(Duration::from_millis(1000)));
+        let tail = make_mock(vec!["switch"], 0, None);
+        assert!(!super::shadowed(&head, &tail));
+    }
+
+    #[test]
+    fn shadowed_if_same_time() {
+        let head = make_mock(vec!["switch"], 0, Some(Duration::from_millis(1000)));
+        let tail = make_mock(vec!["switch"], 0, Some(Duration::from_millis(1000)));
+        assert!(super::shadowed(&head, &tail));
+    }
+
+    #[test]
+    fn regex_shadowed_only_by_same_regex() {
+        let head = make_mock(vec!["~/orders/\\d+"], 0, None);
+        let tail = make_mock(vec!["~/orders/\\d+", "Authorization:"], 0, None);
+        assert!(super::shadowed(&head, &tail));
+
+        let tail = make_mock(vec!["/orders/12"], 0, None);
+        assert!(!super::shadowed(&head, &tail));
+
+        let head = make_mock(vec!["/orders"], 0, None);
+        let tail = make_mock(vec!["~/orders/\\d+"], 0, None);
+        assert!(!super::shadowed(&head, &tail));
+    }
+
+    #[test]
+    fn negated_shadowing() {
+        let head = make_mock(vec!["/orders", "!Authorization:"], 0, None);
+        let tail = make_mock(vec!["/orders", "Authorization:"], 0, None);
+        assert!(!super::shadowed(&head, &tail));
+        assert!(!super::shadowed(&tail, &head));
+
+        // not having the header at all implies not having it with a specific value
+        let head = make_mock(vec!["/orders", "!Authorization: Basic"], 0, None);
+        let tail = make_mock(vec!["/orders", "!Authorization:"], 0, None);
+        assert!(super::shadowed(&head, &tail));
+        assert!(!super::shadowed(&tail, &head));
+    }
+
+    #[test]
+    fn config_not_shadowing() {
+        let config = vec![
+            make_mock(vec!["switch"], 0, None),
+            make_mock(vec!["switch_to_default"], 0, None),
+        ];
+        assert!(!super::verify_mocks_dont_shadow_each_other(&config));
+    }
+
+    #[test]
+    fn config_empty_config_not_shadowing_more_lines() {
+        let config = vec![
+            make_mock(vec!["switch", "header:value1"], 0, None),
+            make_mock(vec!["switch_to_default"], 0, None),
+            make_mock(vec!["switch", "header:value2"], 0, None),
+        ];
+        assert!(super::verify_mocks_dont_shadow_each_other(&config));
+    }
+
+    #[test]
+    fn config_empty_config_not_shadowing() {
+        assert!(super::verify_mocks_dont_shadow_each_other(&[]));
+    }
+}
@@ -0,0 +1,87 @@
+use std::io;
+use std::net::{IpAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+
+use crate::request::RequestReader;
+
+/// How often the idle connections are checked for a new request
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A connection with no request being served
+pub(crate) struct Connection {
+    pub(crate) reader: RequestReader<TcpStream>,
+    pub(crate) client: Option<IpAddr>,
+    /// when the connection was opened or the last response was sent
+    pub(crate) idle_since: Instant,
+}
+
+impl Connection {
+    pub(crate) fn new(reader: RequestReader<TcpStream>, client: Option<IpAddr>) -> Connection {
+        Connection { reader, client, idle_since: Instant::now() }
+    }
+}
+
+enum Readiness {
+    Ready,
+    Idle,
+    Closed,
+}
+
+/// Looks for the next request without blocking
+fn readiness(stream: &TcpStream) -> Readiness {
+    let mut byte = [0u8; 1];
+    match stream.peek(&mut byte) {
+        Ok(0) => Readiness::Closed,
+        Ok(_) => Readiness::Ready,
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Readiness::Idle,
+        Err(_) => Readiness::Closed,
+    }
+}
+
+/// Holds the connections waiting for a request and hands them to the workers once one
+/// arrives, so the workers only hold connections with a request to serve.
+/// The connections idle for longer than the timeout are closed.
+pub(crate) fn watch(idle: Receiver<Connection>, workers: Sender<Connection>, idle_timeout: Duration, shutdown: &AtomicBool) {
+    let mut connections: Vec<Connection> = Vec::new();
+    while !shutdown.load(Ordering::SeqCst) {
+        if connections.is_empty() {
+            // nothing to poll, wait for a connection
+            match idle.recv_timeout(Duration::from_millis(100)) {
+                Ok(connection) => connections.push(connection),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        connections.extend(idle.try_iter());
+
+        let mut still_idle = Vec::with_capacity(connections.len());
+        for connection in connections.drain(..) {
+            let stream = connection.reader.get_ref();
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("Failed to poll a connection: {}", e);
+                continue;
+            }
+            match readiness(stream) {
+                Readiness::Ready => {
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        error!("Failed to poll a connection: {}", e);
+                        continue;
+                    }
+                    if workers.send(connection).is_err() {
+                        return;
+                    }
+                },
+                Readiness::Idle if connection.idle_since.elapsed() < idle_timeout => still_idle.push(connection),
+                Readiness::Idle => debug!("Closing idle connection"),
+                Readiness::Closed => debug!("Connection closed by the client"),
+            }
+        }
+        connections = still_idle;
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+/// A request received by the server
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    /// the request as one piece of text, as the criteria saw it
+    pub request: String,
+    pub method: String,
+    pub target: String,
+    pub time: SystemTime,
+    /// line of the mock that answered, `None` if no mock matched
+    pub line_number: Option<usize>,
+    /// name of the profile active when the request was received
+    pub profile: String,
+    /// session of the request, `None` if the server has no sessions or the request is in none
+    pub session: Option<String>,
+}
+
+impl JournalEntry {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "method": self.method,
+            "target": self.target,
+            "time": self.time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            "line_number": self.line_number,
+            "profile": self.profile,
+            "session": self.session,
+            "request": self.request,
+        })
+    }
+}
+
+/// The requests received so far, the oldest are dropped when the limit is reached
+#[derive(Debug)]
+pub(crate) struct Journal {
+    entries: Mutex<VecDeque<JournalEntry>>,
+    limit: usize,
+}
+
+impl Journal {
+    pub(crate) fn new(limit: usize) -> Journal {
+        Journal { entries: Mutex::new(VecDeque::new()), limit }
+    }
+
+    pub(crate) fn record(&self, entry: JournalEntry) {
+        if self.limit == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.limit {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The requests containing the text, in the order they were received
+    pub(crate) fn find(&self, text: &str) -> Vec<JournalEntry> {
+        self.entries.lock().unwrap().iter().filter(|e| e.request.contains(text)).cloned().collect()
+    }
+
+    pub(crate) fn count(&self, text: &str) -> usize {
+        self.entries.lock().unwrap().iter().filter(|e| e.request.contains(text)).count()
+    }
+
+    /// Removes all the requests, returns how many there were
+    pub(crate) fn clear(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.clear();
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    fn entry(request: &str) -> super::JournalEntry {
+        super::JournalEntry {
+            request: request.to_string(),
+            method: String::from("POST"),
+            target: String::from("/payments"),
+            time: SystemTime::now(),
+            line_number: Some(1),
+            profile: String::from("default"),
+            session: None,
+        }
+    }
+
+    #[test]
+    fn find_and_count() {
+        let journal = super::Journal::new(10);
+        journal.record(entry("POST /payments HTTP/1.1\r\n\r\n{\"amount\": 1}"));
+        journal.record(entry("POST /payments HTTP/1.1\r\n\r\n{\"amount\": 2}"));
+        journal.record(entry("GET /payments HTTP/1.1\r\n\r\n"));
+        assert_eq!(2, journal.count("POST /payments"));
+        assert_eq!(1, journal.find("\"amount\": 2").len());
+        assert_eq!(3, journal.count(""));
+        assert_eq!(3, journal.clear());
+        assert_eq!(0, journal.count(""));
+    }
+
+    #[test]
+    fn limit() {
+        let journal = super::Journal::new(2);
+        journal.record(entry("first"));
+        journal.record(entry("second"));
+        journal.record(entry("third"));
+        assert_eq!(0, journal.count("first"));
+        assert_eq!(2, journal.count(""));
+    }
+}
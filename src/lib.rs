@@ -0,0 +1,40 @@
+//! A mock web server useful for testing.
+//!
+//! The server can be embedded in Rust tests, it listens on a port chosen by
+//! the system and stops when dropped:
+//!
+//! ```
+//! use iron_mockside::{MockServer, Options};
+//!
+//! let config = "GET /hello\nhello.html\n";
+//! let options = Options { base_dir: "mocks".into(), ..Default::default() };
+//! let server = MockServer::start("127.0.0.1:0", config, options).unwrap();
+//! println!("Mock server listening on {}", server.url());
+//! ```
+
+mod admin;
+mod clock;
+mod config;
+mod idle;
+mod journal;
+mod proxy;
+mod record;
+mod request;
+mod response;
+mod server;
+mod session;
+#[cfg(test)]
+mod test_dir;
+mod watch;
+
+pub use config::{
+    check_config_file, find_missing_response_files, find_shadowed_mocks, find_timers_never_reset,
+    find_dead_end_states, find_unreachable_states, find_unreferenced_profiles, process_config_file,
+    verify_all_profiles_are_referenced, verify_mocks_dont_shadow_each_other, verify_response_files_exist, ConfigError,
+    ConfigReport, Mock,
+};
+pub use journal::JournalEntry;
+pub use server::{
+    Error, MockServer, Options, Position, DEFAULT_IDLE_TIMEOUT, DEFAULT_JOURNAL_LIMIT, DEFAULT_MAX_BODY, DEFAULT_RELOAD_INTERVAL,
+    DEFAULT_WORKERS,
+};
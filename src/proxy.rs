@@ -0,0 +1,215 @@
+use std::io;
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::request::{find_head_end, Request};
+use crate::response::header_value;
+
+/// How long to wait for the upstream to answer
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers about the connection to the client, they are not forwarded
+const HOP_BY_HOP: [&str; 9] = [
+    "Host", "Connection", "Keep-Alive", "Proxy-Connection", "Transfer-Encoding",
+    "Content-Length", "TE", "Upgrade", "Expect",
+];
+
+/// The server the requests no mock matches are forwarded to, only plain http is supported
+#[derive(Debug, Clone)]
+pub(crate) struct Upstream {
+    pub(crate) url: String,
+    /// host and port to connect to
+    address: String,
+    /// value of the Host header
+    host: String,
+    /// path prepended to the request targets, without the trailing /
+    prefix: String,
+}
+
+impl Upstream {
+    /// Parses an url like `http://localhost:8080/api`
+    pub(crate) fn parse(url: &str) -> Result<Upstream, &'static str> {
+        let rest = match url.get(..7) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &url[7..],
+            _ => return Err("the upstream url must start with http://"),
+        };
+        let (host, prefix) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], rest[slash..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err("the upstream url has no host");
+        }
+        // an IPv6 address is written in brackets, its colons are not the port separator
+        let has_port = match host.rfind(':') {
+            Some(colon) => !host[colon..].contains(']'),
+            None => false,
+        };
+        let address = if has_port { host.to_string() } else { format!("{}:80", host) };
+        Ok(Upstream {
+            url: url.to_string(),
+            address,
+            host: host.to_string(),
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+/// Sends the request to the upstream and returns its response
+pub(crate) fn forward(request: &Request, upstream: &Upstream) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(&upstream.address)?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    stream.write_all(&upstream_request(request, upstream))?;
+    stream.flush()?;
+    read_response(stream, request.method == "HEAD")
+}
+
+/// The request as sent to the upstream: the target gets the path of the upstream url,
+/// a chunked body is sent decoded with its length and the connection is closed after the response
+fn upstream_request(request: &Request, upstream: &Upstream) -> Vec<u8> {
+    let mut head = format!("{} {}{} {}\r\nHost: {}\r\n", request.method, upstream.prefix, request.target, request.version, upstream.host);
+    for (name, value) in &request.headers {
+        if !HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    let had_body = request.header("Content-Length").is_some() || request.header("Transfer-Encoding").is_some();
+    if had_body || !request.body.is_empty() {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(&request.body);
+    bytes
+}
+
+/// Reads one response, the connection can stay open after it
+fn read_response<R: Read>(mut stream: R, head_request: bool) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        let count = stream.read(&mut chunk)?;
+        let eof = count == 0;
+        buffer.extend_from_slice(&chunk[..count]);
+        if let Some((start, end)) = response_bounds(&buffer, head_request, eof) {
+            buffer.truncate(end);
+            buffer.drain(..start);
+            return Ok(buffer);
+        }
+        if eof {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the upstream closed the connection before the end of the response"));
+        }
+    }
+}
+
+/// Where the final response starts and ends in the buffer, `None` if more bytes are needed.
+/// Interim 1xx responses are skipped, a body without a length ends with the connection.
+fn response_bounds(buffer: &[u8], head_request: bool, eof: bool) -> Option<(usize, usize)> {
+    let mut start = 0;
+    loop {
+        let head_end = start + find_head_end(&buffer[start..])?;
+        let head = String::from_utf8_lossy(&buffer[start..head_end]);
+        let mut lines = head.lines().map(|l| l.trim_end_matches('\r'));
+        let status = lines.next()
+            .and_then(|l| l.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok())?;
+        if (100..200).contains(&status) && status != 101 {
+            start = head_end;
+            continue;
+        }
+
+        let headers: Vec<&str> = lines.take_while(|l| !l.is_empty()).collect();
+        let header = |name| headers.iter().find_map(|l| header_value(l, name));
+        let body = &buffer[head_end..];
+        let length = if head_request || status < 200 || status == 204 || status == 304 {
+            Some(0)
+        } else if header("Transfer-Encoding").is_some_and(|v| v.to_ascii_lowercase().ends_with("chunked")) {
+            chunked_length(body)
+        } else if let Some(length) = header("Content-Length").and_then(|v| v.parse::<usize>().ok()) {
+            Some(length).filter(|l| *l <= body.len())
+        } else if eof {
+            Some(body.len())
+        } else {
+            None
+        };
+        return length.map(|l| (start, head_end + l));
+    }
+}
+
+/// Length of the chunked body at the start of the buffer including the trailers,
+/// `None` if it is not complete
+fn chunked_length(buffer: &[u8]) -> Option<usize> {
+    let line_end = |from: usize| buffer.get(from..)?.iter().position(|b| *b == b'\n').map(|p| from + p + 1);
+    let mut position = 0;
+    loop {
+        let next = line_end(position)?;
+        let line = String::from_utf8_lossy(&buffer[position..next]);
+        let size = usize::from_str_radix(line.split(';').next()?.trim(), 16).ok()?;
+        position = next;
+        if size == 0 {
+            // the trailers end with an empty line
+            loop {
+                let next = line_end(position)?;
+                let empty = buffer[position..next].iter().all(|b| *b == b'\r' || *b == b'\n');
+                position = next;
+                if empty {
+                    return Some(position);
+                }
+            }
+        }
+        // the data is followed by a line ending, a size overflowing the position is malformed
+        position = line_end(position.checked_add(size)?)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::request::RequestReader;
+
+    #[test]
+    fn parse_upstream() {
+        let upstream = super::Upstream::parse("http://localhost:8080/api/").unwrap();
+        assert_eq!("localhost:8080", upstream.address);
+        assert_eq!("/api", upstream.prefix);
+        let upstream = super::Upstream::parse("HTTP://example.com").unwrap();
+        assert_eq!("example.com:80", upstream.address);
+        assert_eq!("example.com", upstream.host);
+        assert_eq!("[::1]:80", super::Upstream::parse("http://[::1]").unwrap().address);
+        assert!(super::Upstream::parse("https://example.com").is_err());
+        assert!(super::Upstream::parse("http:///path").is_err());
+    }
+
+    #[test]
+    fn request_for_upstream() {
+        let wire = b"POST /orders HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nX-Id: 7\r\n\r\n2\r\nok\r\n0\r\n\r\n";
+        let request = RequestReader::new(io::Cursor::new(wire.to_vec()), 1024).read_request().unwrap().unwrap();
+        let upstream = super::Upstream::parse("http://backend:9000/api").unwrap();
+        assert_eq!(
+            "POST /api/orders HTTP/1.1\r\nHost: backend:9000\r\nX-Id: 7\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+            String::from_utf8(super::upstream_request(&request, &upstream)).unwrap()
+        );
+    }
+
+    #[test]
+    fn bounds() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokextra";
+        assert_eq!(Some((0, 40)), super::response_bounds(response, false, false));
+        assert_eq!(None, super::response_bounds(&response[..39], false, false));
+        assert_eq!(Some((0, 38)), super::response_bounds(response, true, false));
+
+        let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n\r\nbody";
+        assert_eq!(None, super::response_bounds(response, false, false));
+        assert_eq!(Some((25, response.len())), super::response_bounds(response, false, true));
+
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2;x=y\r\nok\r\n0\r\nTrailer: 1\r\n\r\n";
+        assert_eq!(Some((0, response.len())), super::response_bounds(response, false, false));
+        assert_eq!(None, super::response_bounds(&response[..response.len() - 2], false, false));
+
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+        assert_eq!(None, super::response_bounds(response, false, true));
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::*;
+
+use crate::config::{parse_config, parse_source, shadowed, Mock, Profiles};
+use crate::request::{find_head_end, Request};
+use crate::response::header_value;
+
+/// Headers left out of the recorded responses, the length is computed when the mock is served
+const DROPPED_HEADERS: [&str; 3] = ["Content-Length", "Transfer-Encoding", "Connection"];
+
+/// Writes the exchanges with the upstream as mocks: a group appended to the config file
+/// and the response split in a headers file and a body file next to it
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    config_file: PathBuf,
+    /// request headers added to the criteria
+    headers: Vec<String>,
+    /// the mocks in the file, starting with the ones there before the recording
+    recorded: Mutex<Vec<Mock>>,
+}
+
+impl Recorder {
+    pub(crate) fn new(config_file: PathBuf, headers: Vec<String>) -> Recorder {
+        // the groups recorded by an earlier run are not recorded again
+        let recorded = fs::read_to_string(&config_file).ok()
+            .and_then(|text| {
+                let dir = config_file.parent().unwrap_or_else(|| Path::new(""));
+                parse_source(&text, Some(&config_file), dir, &mut Profiles::new()).ok()
+            })
+            .unwrap_or_default();
+        Recorder { config_file, headers, recorded: Mutex::new(recorded) }
+    }
+
+    /// Appends the mock for this exchange, unless a mock in the file already answers it
+    pub(crate) fn record(&self, request: &Request, response: &[u8]) -> io::Result<()> {
+        let criteria = self.criteria(request);
+        // the files line doesn't matter to the shadowing
+        let mock = match parse_config(&format!("{}\nrecorded\n", criteria.join("\n")), &mut Profiles::new()) {
+            Ok(mut mocks) if mocks.len() == 1 => mocks.remove(0),
+            _ => {
+                warn!("Can't record {}, its criteria are not valid", criteria[0]);
+                return Ok(());
+            },
+        };
+        // the lock also keeps the writes of concurrent requests apart
+        let mut recorded = self.recorded.lock().unwrap();
+        if let Some(earlier) = recorded.iter().find(|m| shadowed(m, &mock)) {
+            debug!("Already recorded {} at {}", criteria[0], earlier.location());
+            return Ok(());
+        }
+
+        let dir = self.config_file.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        let base = file_name(request);
+        let mut n = 1;
+        while dir.join(format!("{}-{}.headers", base, n)).exists() {
+            n += 1;
+        }
+        let headers_file = format!("{}-{}.headers", base, n);
+        let body_file = format!("{}-{}.body", base, n);
+
+        let (head, body) = split_response(response);
+        fs::write(dir.join(&headers_file), head)?;
+        let mut files = format!("`content-length;{}", headers_file);
+        if !body.is_empty() {
+            fs::write(dir.join(&body_file), body)?;
+            files.push(';');
+            files.push_str(&body_file);
+        }
+
+        // the groups are separated by an empty line
+        let previous = fs::read(&self.config_file).unwrap_or_default();
+        let separator = match previous.len() {
+            0 => "",
+            _ if previous.ends_with(b"\n\n") || previous.ends_with(b"\n\r\n") => "",
+            _ if previous.ends_with(b"\n") => "\n",
+            _ => "\n\n",
+        };
+        let mut config = OpenOptions::new().create(true).append(true).open(&self.config_file)?;
+        write!(config, "{}{}\n{}\n", separator, criteria.join("\n"), files)?;
+
+        info!("Recorded {} in {}", criteria[0], self.config_file.display());
+        recorded.push(mock);
+        Ok(())
+    }
+
+    /// The request line without the version and the selected headers
+    fn criteria(&self, request: &Request) -> Vec<String> {
+        // keeping "HTTP/" stops /orders from matching /orders/1 too
+        let mut criteria = vec![format!("{} {} HTTP/", request.method, request.target)];
+        for (name, value) in &request.headers {
+            if self.headers.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+                criteria.push(format!("{}: {}", name, value));
+            }
+        }
+        criteria
+    }
+}
+
+/// Base of the file names for the request, e.g. `get-orders-7` for `GET /orders/7`
+fn file_name(request: &Request) -> String {
+    let path = request.target.split('?').next().unwrap_or_default();
+    let mut name = request.method.to_lowercase();
+    for part in path.split(|c: char| !c.is_ascii_alphanumeric()).filter(|p| !p.is_empty()) {
+        if name.len() + part.len() > 60 {
+            break;
+        }
+        name.push('-');
+        name.push_str(&part.to_ascii_lowercase());
+    }
+    name
+}
+
+/// The status line and the headers, without the ones about the length, and the decoded body
+fn split_response(response: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let head_end = find_head_end(response).unwrap_or(response.len());
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let mut chunked = false;
+    let mut result = Vec::with_capacity(head_end);
+    for line in head.lines().map(|l| l.trim_end_matches('\r')).take_while(|l| !l.is_empty()) {
+        if header_value(line, "Transfer-Encoding").is_some_and(|v| v.to_ascii_lowercase().ends_with("chunked")) {
+            chunked = true;
+        }
+        if DROPPED_HEADERS.iter().any(|h| header_value(line, h).is_some()) {
+            continue;
+        }
+        result.extend_from_slice(line.as_bytes());
+        result.extend_from_slice(b"\r\n");
+    }
+    result.extend_from_slice(b"\r\n");
+
+    let body = &response[head_end..];
+    let body = if chunked { decode_chunked(body) } else { body.to_vec() };
+    (result, body)
+}
+
+/// The data of the chunks, whatever follows the last chunk is ignored
+fn decode_chunked(mut body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(body.len());
+    while let Some(line_end) = body.iter().position(|b| *b == b'\n') {
+        let line = String::from_utf8_lossy(&body[..line_end]);
+        let size = match usize::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16) {
+            Ok(size) if size > 0 => size,
+            _ => break,
+        };
+        let chunk = &body[line_end + 1..];
+        let size = size.min(chunk.len());
+        data.extend_from_slice(&chunk[..size]);
+        // skip the line ending after the data
+        body = &chunk[size..];
+        body = match body.iter().position(|b| *b == b'\n') {
+            Some(end) => &body[end + 1..],
+            None => break,
+        };
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::request::RequestReader;
+
+    fn request(wire: &str) -> crate::request::Request {
+        RequestReader::new(io::Cursor::new(wire.as_bytes().to_vec()), 1024).read_request().unwrap().unwrap()
+    }
+
+    #[test]
+    fn criteria_and_name() {
+        let recorder = super::Recorder::new("config.txt".into(), vec![String::from("accept")]);
+        let request = request("GET /orders/7?full=1 HTTP/1.1\r\nHost: x\r\nAccept: application/json\r\n\r\n");
+        assert_eq!(vec!["GET /orders/7?full=1 HTTP/", "Accept: application/json"], recorder.criteria(&request));
+        assert_eq!("get-orders-7", super::file_name(&request));
+    }
+
+    #[test]
+    fn record_once() {
+        let dir = crate::test_dir::TestDir::new("record-once");
+        let config_file = dir.join("config.txt");
+        std::fs::write(&config_file, "GET /orders HTTP/\n`content-length;get-orders-1.headers\n").unwrap();
+        let recorder = super::Recorder::new(config_file.clone(), vec![String::from("accept")]);
+        let response = b"HTTP/1.1 200 OK\r\n\r\n";
+        // shadowed by the group of an earlier run
+        recorder.record(&request("GET /orders HTTP/1.1\r\nAccept: application/json\r\n\r\n"), response).unwrap();
+        recorder.record(&request("GET /orders/7 HTTP/1.1\r\n\r\n"), response).unwrap();
+        recorder.record(&request("GET /orders/7 HTTP/1.1\r\nAccept: text/plain\r\n\r\n"), response).unwrap();
+
+        let config = std::fs::read_to_string(&config_file).unwrap();
+        assert_eq!(2, config.matches("`content-length").count(), "{}", config);
+        assert!(!config.contains("text/plain"));
+        assert!(crate::config::check_config_file(&config_file).unwrap().errors.iter().all(|e| e.message == "Could not find response file"));
+    }
+
+    #[test]
+    fn split() {
+        let (head, body) = super::split_response(b"HTTP/1.1 200 OK\nTransfer-Encoding: chunked\nX-Id: 1\n\n2\r\nok\r\n3;ext\r\n!!!\r\n0\r\n\r\n");
+        assert_eq!(&b"HTTP/1.1 200 OK\r\nX-Id: 1\r\n\r\n"[..], &head[..]);
+        assert_eq!(&b"ok!!!"[..], &body[..]);
+        let (head, body) = super::split_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        assert_eq!(&b"HTTP/1.1 200 OK\r\n\r\n"[..], &head[..]);
+        assert_eq!(&b"ok"[..], &body[..]);
+    }
+}
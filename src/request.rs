@@ -0,0 +1,501 @@
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+use log::debug;
+
+/// Requests with a bigger header section are rejected
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// An HTTP request as read from the socket
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    /// request line and headers, including the empty line ending them
+    pub head: Vec<u8>,
+    /// body without the transfer encoding
+    pub body: Vec<u8>,
+    /// fields sent after a chunked body
+    pub trailers: Vec<(String, String)>,
+    /// body as received, only set if it had a transfer encoding
+    pub wire_body: Option<Vec<u8>>,
+}
+
+impl Request {
+    /// Value of the first header or trailer with this name, names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .chain(self.trailers.iter())
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The full request as one piece of text, this is what the criteria are matched against.
+    /// A chunked body is decoded and its trailers are listed after the other headers.
+    pub fn text(&self) -> String {
+        let mut text = Vec::with_capacity(self.head.len() + self.body.len());
+        if self.trailers.is_empty() {
+            text.extend_from_slice(&self.head);
+        } else {
+            // insert the trailers before the empty line ending the head
+            let head = String::from_utf8_lossy(&self.head);
+            let eol = if head.ends_with("\r\n") { "\r\n" } else { "\n" };
+            text.extend_from_slice(&self.head[..self.head.len() - eol.len()]);
+            for (name, value) in &self.trailers {
+                text.extend_from_slice(format!("{}: {}{}", name, value, eol).as_bytes());
+            }
+            text.extend_from_slice(eol.as_bytes());
+        }
+        text.extend_from_slice(&self.body);
+        String::from_utf8_lossy(&text).into_owned()
+    }
+
+    /// Whether the client wants to send more requests on the same connection
+    pub fn keep_alive(&self) -> bool {
+        let connection = |token: &str| self.header("Connection")
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+        if self.version == "HTTP/1.0" {
+            connection("keep-alive")
+        } else {
+            !connection("close")
+        }
+    }
+
+    /// The request exactly as received on the wire
+    pub fn wire_text(&self) -> String {
+        let body = self.wire_body.as_ref().unwrap_or(&self.body);
+        let mut raw = Vec::with_capacity(self.head.len() + body.len());
+        raw.extend_from_slice(&self.head);
+        raw.extend_from_slice(body);
+        String::from_utf8_lossy(&raw).into_owned()
+    }
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    Io(io::Error),
+    Malformed(&'static str),
+    HeadTooLarge,
+    BodyTooLarge(usize),
+}
+
+impl RequestError {
+    /// Response to send back to the client before closing the connection
+    pub fn response(&self) -> Option<&'static [u8]> {
+        match self {
+            RequestError::Io(_) => None,
+            RequestError::Malformed(_) => Some(b"HTTP/1.1 400 Bad Request\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"),
+            RequestError::HeadTooLarge => Some(b"HTTP/1.1 431 Request Header Fields Too Large\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"),
+            RequestError::BodyTooLarge(_) => Some(b"HTTP/1.1 413 Payload Too Large\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Io(e) => write!(f, "{}", e),
+            RequestError::Malformed(reason) => write!(f, "malformed request, {}", reason),
+            RequestError::HeadTooLarge => write!(f, "request headers longer than {} bytes", MAX_HEAD_SIZE),
+            RequestError::BodyTooLarge(length) => write!(f, "request body of {} bytes is over the limit", length),
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+/// Returns the position after the empty line ending the headers, if there is one.
+/// Bare \n line endings are accepted as well as \r\n.
+pub fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    let mut count = 0;
+    for (i, &b) in buffer.iter().enumerate() {
+        match b {
+            b'\r' => (),
+            b'\n' => {
+                count += 1;
+                if count == 2 {
+                    return Some(i + 1);
+                }
+            }
+            _ => count = 0,
+        }
+    }
+    None
+}
+
+/// Reads the requests sent one after the other on a connection.
+/// The bytes received after the end of a request are kept for the next one,
+/// so pipelined requests are not lost.
+pub struct RequestReader<S> {
+    stream: S,
+    /// bytes received from the stream but not consumed yet
+    buffer: Vec<u8>,
+    max_body: usize,
+}
+
+impl<S: Read + Write> RequestReader<S> {
+    pub fn new(stream: S, max_body: usize) -> RequestReader<S> {
+        RequestReader { stream, buffer: Vec::with_capacity(4096), max_body }
+    }
+
+    /// The underlying stream, used to write the responses
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// True if bytes of the next request were already received, e.g. a pipelined request
+    pub fn is_buffered(&self) -> bool {
+        !self.buffer.iter().all(|b| b.is_ascii_whitespace())
+    }
+
+    /// Reads more bytes from the stream, returns false at the end of the stream
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        let count = self.stream.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..count]);
+        Ok(count > 0)
+    }
+
+    fn take(&mut self, count: usize) -> Result<Vec<u8>, RequestError> {
+        while self.buffer.len() < count {
+            if !self.fill()? {
+                return Err(RequestError::Malformed("connection closed before the end of the body"));
+            }
+        }
+        let rest = self.buffer.split_off(count);
+        Ok(std::mem::replace(&mut self.buffer, rest))
+    }
+
+    /// Takes a line, including its terminator
+    fn take_line(&mut self) -> Result<Vec<u8>, RequestError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                return self.take(end + 1);
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(RequestError::HeadTooLarge);
+            }
+            if !self.fill()? {
+                return Err(RequestError::Malformed("connection closed in the middle of a chunked body"));
+            }
+        }
+    }
+
+    /// Reads one request from the stream: request line, headers and the body,
+    /// either exactly `Content-Length` bytes or decoded from the chunked transfer encoding.
+    /// Returns `None` if the client closed the connection without sending anything.
+    pub fn read_request(&mut self) -> Result<Option<Request>, RequestError> {
+        let head_end = loop {
+            // skip the line endings some clients send after a body
+            let start = self.buffer.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(self.buffer.len());
+            self.buffer.drain(..start);
+            if let Some(end) = find_head_end(&self.buffer) {
+                break end;
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                return Err(RequestError::HeadTooLarge);
+            }
+            if !self.fill()? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(RequestError::Malformed("connection closed before the end of the headers"));
+            }
+        };
+
+        let head = self.take(head_end)?;
+        let mut request = parse_head(head)?;
+
+        let chunked = match request.header("Transfer-Encoding") {
+            Some(encoding) if encoding.rsplit(',').next().unwrap().trim().eq_ignore_ascii_case("chunked") => true,
+            Some(_) => return Err(RequestError::Malformed("unsupported transfer encoding")),
+            None => false,
+        };
+        let content_length = if chunked { 0 } else { content_length(&request)? };
+        if content_length > self.max_body {
+            return Err(RequestError::BodyTooLarge(content_length));
+        }
+
+        let body_pending = chunked || self.buffer.len() < content_length;
+        if body_pending && request.header("Expect").is_some_and(|v| v.eq_ignore_ascii_case("100-continue")) {
+            debug!("Send a continue response");
+            self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+
+        if chunked {
+            self.read_chunked_body(&mut request)?;
+        } else {
+            request.body = self.take(content_length)?;
+        }
+        Ok(Some(request))
+    }
+
+    /// Decodes a chunked body, the trailer fields are added to the request
+    fn read_chunked_body(&mut self, request: &mut Request) -> Result<(), RequestError> {
+        let mut wire = Vec::new();
+        let mut body = Vec::new();
+        loop {
+            let line = self.take_line()?;
+            wire.extend_from_slice(&line);
+            let size = String::from_utf8_lossy(&line);
+            // chunk extensions are ignored
+            let size = size.split(';').next().unwrap().trim();
+            let size = usize::from_str_radix(size, 16).map_err(|_| RequestError::Malformed("invalid chunk size"))?;
+            if size == 0 {
+                break;
+            }
+            // a size near usize::MAX would overflow the total
+            let total = body.len().checked_add(size).ok_or(RequestError::BodyTooLarge(usize::MAX))?;
+            if total > self.max_body {
+                return Err(RequestError::BodyTooLarge(total));
+            }
+            let data = self.take(size)?;
+            body.extend_from_slice(&data);
+            wire.extend_from_slice(&data);
+            let end = self.take_line()?;
+            if !end.iter().all(|b| b.is_ascii_whitespace()) {
+                return Err(RequestError::Malformed("chunk longer than its size"));
+            }
+            wire.extend_from_slice(&end);
+        }
+
+        let mut trailer_size = 0;
+        loop {
+            let line = self.take_line()?;
+            wire.extend_from_slice(&line);
+            trailer_size += line.len();
+            if trailer_size > MAX_HEAD_SIZE {
+                return Err(RequestError::HeadTooLarge);
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                break;
+            }
+            match line.find(':') {
+                Some(colon) => request.trailers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string())),
+                None => return Err(RequestError::Malformed("trailer line without colon")),
+            }
+        }
+
+        request.body = body;
+        request.wire_body = Some(wire);
+        Ok(())
+    }
+}
+
+fn parse_head(head: Vec<u8>) -> Result<Request, RequestError> {
+    let text = String::from_utf8_lossy(&head).into_owned();
+    // tolerate empty lines before the request line
+    let mut lines = text.lines().map(|l| l.trim_end_matches('\r')).skip_while(|l| l.is_empty());
+
+    let request_line = lines.next().ok_or(RequestError::Malformed("missing request line"))?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/") => (method, target, version),
+        _ => return Err(RequestError::Malformed("invalid request line")),
+    };
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|l| !l.is_empty()) {
+        match line.find(':') {
+            Some(colon) => headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string())),
+            None => return Err(RequestError::Malformed("header line without colon")),
+        }
+    }
+
+    Ok(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+        head,
+        body: Vec::new(),
+        trailers: Vec::new(),
+        wire_body: None,
+    })
+}
+
+fn content_length(request: &Request) -> Result<usize, RequestError> {
+    let mut result = None;
+    for (_, value) in request.headers.iter().filter(|(n, _)| n.eq_ignore_ascii_case("Content-Length")) {
+        let length = value.parse().map_err(|_| RequestError::Malformed("invalid Content-Length"))?;
+        if result.is_some_and(|l| l != length) {
+            return Err(RequestError::Malformed("conflicting Content-Length headers"));
+        }
+        result = Some(length);
+    }
+    Ok(result.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+
+    /// Serves the input in fixed size pieces, like a socket receiving several segments
+    struct Segmented {
+        input: Vec<u8>,
+        position: usize,
+        segment: usize,
+        written: Vec<u8>,
+    }
+
+    impl Segmented {
+        fn new(input: &[u8], segment: usize) -> Segmented {
+            Segmented { input: input.to_vec(), position: 0, segment, written: Vec::new() }
+        }
+    }
+
+    impl Read for Segmented {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let end = (self.position + self.segment).min(self.input.len());
+            let count = (end - self.position).min(buf.len());
+            buf[..count].copy_from_slice(&self.input[self.position..self.position + count]);
+            self.position += count;
+            Ok(count)
+        }
+    }
+
+    impl Write for Segmented {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn head_end() {
+        assert_eq!(Some(8), super::find_head_end(b"head\r\n\r\nbody"));
+        assert_eq!(None, super::find_head_end(b"head"));
+        assert_eq!(None, super::find_head_end(b"head\r\n"));
+        assert_eq!(Some(6), super::find_head_end(b"head\n\nbody"));
+    }
+
+    #[test]
+    fn read_get() {
+        let mut stream = Segmented::new(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n", 1000);
+        let request = super::RequestReader::new(&mut stream, 100).read_request().unwrap().unwrap();
+        assert_eq!("GET", request.method);
+        assert_eq!("/hello", request.target);
+        assert_eq!(Some("localhost"), request.header("host"));
+        assert!(request.body.is_empty());
+        assert_eq!("GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n", request.text());
+    }
+
+    #[test]
+    fn read_body_in_many_segments() {
+        let body = "x".repeat(50_000);
+        let raw = format!("POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let mut stream = Segmented::new(raw.as_bytes(), 7);
+        let request = super::RequestReader::new(&mut stream, 100_000).read_request().unwrap().unwrap();
+        assert_eq!(body.as_bytes(), &request.body[..]);
+    }
+
+    #[test]
+    fn body_over_limit() {
+        let mut stream = Segmented::new(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello world", 1000);
+        match super::RequestReader::new(&mut stream, 10).read_request() {
+            Err(super::RequestError::BodyTooLarge(11)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn expect_continue() {
+        let mut stream = Segmented::new(b"POST / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nok", 20);
+        let request = super::RequestReader::new(&mut stream, 10).read_request().unwrap().unwrap();
+        assert_eq!(b"ok", &request.body[..]);
+        assert_eq!(b"HTTP/1.1 100 Continue\r\n\r\n", &stream.written[..]);
+    }
+
+    #[test]
+    fn truncated_body() {
+        let mut stream = Segmented::new(b"POST / HTTP/1.1\r\nContent-Length: 20\r\n\r\nshort", 1000);
+        assert!(super::RequestReader::new(&mut stream, 100).read_request().is_err());
+    }
+
+    #[test]
+    fn read_chunked() {
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: abc\r\n\r\n";
+        let mut stream = Segmented::new(raw, 3);
+        let request = super::RequestReader::new(&mut stream, 100).read_request().unwrap().unwrap();
+        assert_eq!(b"hello world", &request.body[..]);
+        assert_eq!(Some("abc"), request.header("x-checksum"));
+        assert_eq!(
+            "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\nX-Checksum: abc\r\n\r\nhello world",
+            request.text()
+        );
+        assert_eq!(String::from_utf8_lossy(raw), request.wire_text());
+    }
+
+    #[test]
+    fn chunked_over_limit() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut stream = Segmented::new(raw, 1000);
+        match super::RequestReader::new(&mut stream, 10).read_request() {
+            Err(super::RequestError::BodyTooLarge(11)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chunked_size_overflow() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+        let mut stream = Segmented::new(raw, 1000);
+        match super::RequestReader::new(&mut stream, 10).read_request() {
+            Err(super::RequestError::BodyTooLarge(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chunked_bad_size() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n";
+        let mut stream = Segmented::new(raw, 1000);
+        assert!(super::RequestReader::new(&mut stream, 100).read_request().is_err());
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let raw = b"POST /first HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /second HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut stream = Segmented::new(raw, 1000);
+        let mut reader = super::RequestReader::new(&mut stream, 100);
+        let first = reader.read_request().unwrap().unwrap();
+        assert_eq!("/first", first.target);
+        assert_eq!(b"hello", &first.body[..]);
+        assert!(first.keep_alive());
+        let second = reader.read_request().unwrap().unwrap();
+        assert_eq!("/second", second.target);
+        assert!(!second.keep_alive());
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn keep_alive_http_1_0() {
+        let mut stream = Segmented::new(b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", 1000);
+        let mut reader = super::RequestReader::new(&mut stream, 100);
+        assert!(!reader.read_request().unwrap().unwrap().keep_alive());
+        assert!(reader.read_request().unwrap().unwrap().keep_alive());
+    }
+
+    #[test]
+    fn closed_without_request() {
+        let mut stream = Segmented::new(b"", 1000);
+        assert!(super::RequestReader::new(&mut stream, 100).read_request().unwrap().is_none());
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use crate::request::{find_head_end, Request};
+
+/// Checks if the client can tell where this response ends without the connection
+/// being closed, i.e. it has no body or its body length is known.
+/// Mock responses are sent verbatim, without a length the connection has to be closed.
+pub fn is_delimited(response: &[u8]) -> bool {
+    let head_end = match find_head_end(response) {
+        Some(end) => end,
+        // no empty line after the headers, only closing the connection ends it
+        None => return false,
+    };
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    let mut lines = head.lines().map(|l| l.trim_end_matches('\r'));
+
+    let status = lines.next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok());
+    match status {
+        None => return false,
+        Some(code) if code < 200 || code == 204 || code == 304 => return true,
+        _ => (),
+    }
+
+    let mut delimited = false;
+    for line in lines.take_while(|l| !l.is_empty()) {
+        if let Some(colon) = line.find(':') {
+            let name = line[..colon].trim();
+            let value = line[colon + 1..].trim();
+            if name.eq_ignore_ascii_case("Connection") && value.eq_ignore_ascii_case("close") {
+                return false;
+            }
+            if name.eq_ignore_ascii_case("Content-Length")
+                || (name.eq_ignore_ascii_case("Transfer-Encoding") && value.to_ascii_lowercase().ends_with("chunked")) {
+                delimited = true;
+            }
+        }
+    }
+    delimited
+}
+
+/// Sets the content-length header to the real length of the body,
+/// adding it if missing. The line endings of the status line and headers
+/// are normalized to CRLF, the body is left untouched.
+/// Responses with a chunked body or that can't have a body only get the line endings fixed.
+pub fn set_content_length(response: &[u8]) -> Vec<u8> {
+    // without an empty line everything is considered to be headers
+    let head_end = find_head_end(response).unwrap_or(response.len());
+    let (head, body) = response.split_at(head_end);
+    let head = String::from_utf8_lossy(head);
+    let mut lines: Vec<&str> = head.lines().map(|l| l.trim_end_matches('\r')).collect();
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+
+    let status = lines.first()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok());
+    let has_body = !matches!(status, Some(code) if code < 200 || code == 204 || code == 304);
+    let chunked = lines.iter().skip(1).any(|l| header_value(l, "Transfer-Encoding")
+        .is_some_and(|v| v.to_ascii_lowercase().ends_with("chunked")));
+
+    let mut result = Vec::with_capacity(response.len() + 32);
+    let mut length_written = false;
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 && header_value(line, "Content-Length").is_some() {
+            // replace the first one in place, drop the others
+            if has_body && !chunked && !length_written {
+                result.extend_from_slice(format!("content-length: {}\r\n", body.len()).as_bytes());
+                length_written = true;
+            }
+            continue;
+        }
+        result.extend_from_slice(line.as_bytes());
+        result.extend_from_slice(b"\r\n");
+    }
+    if has_body && !chunked && !length_written {
+        result.extend_from_slice(format!("content-length: {}\r\n", body.len()).as_bytes());
+    }
+    result.extend_from_slice(b"\r\n");
+    result.extend_from_slice(body);
+    result
+}
+
+/// Replaces the placeholders in the response: `{{name}}` with the value captured by the
+/// named group of a regular expression criterion and `{{header.Name}}` with the value
+/// of a request header. Unknown placeholders are left as they are.
+pub fn fill_template(response: &[u8], captures: &HashMap<String, String>, request: &Request) -> Vec<u8> {
+    let mut result = Vec::with_capacity(response.len());
+    let mut rest = response;
+    while let Some(start) = find(rest, b"{{") {
+        let end = match find(&rest[start + 2..], b"}}") {
+            Some(end) => start + 2 + end,
+            None => break,
+        };
+        result.extend_from_slice(&rest[..start]);
+        let name = String::from_utf8_lossy(&rest[start + 2..end]);
+        let name = name.trim();
+        let value = match name.strip_prefix("header.") {
+            Some(header) => request.header(header),
+            None => captures.get(name).map(|v| v.as_str()),
+        };
+        match value {
+            Some(value) => result.extend_from_slice(value.as_bytes()),
+            None => result.extend_from_slice(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    result.extend_from_slice(rest);
+    result
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Value of the header on this line if it has the given name
+pub(crate) fn header_value<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let colon = line.find(':')?;
+    if line[..colon].trim().eq_ignore_ascii_case(name) {
+        Some(line[colon + 1..].trim())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn delimited() {
+        assert!(super::is_delimited(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"));
+        assert!(super::is_delimited(b"HTTP/1.1 200 OK\ntransfer-encoding: chunked\n\n2\r\nok\r\n0\r\n\r\n"));
+        assert!(super::is_delimited(b"HTTP/1.1 204 No Content\r\n\r\n"));
+    }
+
+    #[test]
+    fn not_delimited() {
+        assert!(!super::is_delimited(b"HTTP/1.1 200 OK\n\nhello"));
+        assert!(!super::is_delimited(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"));
+        assert!(!super::is_delimited(b"<html>no status line</html>"));
+    }
+
+    #[test]
+    fn add_content_length() {
+        let response = super::set_content_length(b"HTTP/1.1 200 OK\ncontent-type: text/plain\n\nhello\n");
+        assert_eq!(&b"HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\ncontent-length: 6\r\n\r\nhello\n"[..], &response[..]);
+    }
+
+    #[test]
+    fn correct_content_length() {
+        let response = super::set_content_length(b"HTTP/1.1 200 OK\r\nContent-Length: 4286\r\nserver: mock\r\n\r\nok");
+        assert_eq!(&b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nserver: mock\r\n\r\nok"[..], &response[..]);
+    }
+
+    #[test]
+    fn content_length_without_body() {
+        let response = super::set_content_length(b"HTTP/1.1 200 OK\nserver: mock\n");
+        assert_eq!(&b"HTTP/1.1 200 OK\r\nserver: mock\r\ncontent-length: 0\r\n\r\n"[..], &response[..]);
+        let response = super::set_content_length(b"HTTP/1.1 204 No Content\n\n");
+        assert_eq!(&b"HTTP/1.1 204 No Content\r\n\r\n"[..], &response[..]);
+    }
+
+    #[test]
+    fn content_length_not_added_to_chunked() {
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n";
+        assert_eq!(&chunked[..], &super::set_content_length(chunked)[..]);
+    }
+
+    #[test]
+    fn template() {
+        let stream = std::io::Cursor::new(b"GET /orders/42 HTTP/1.1\r\nX-Request-Id: abc\r\n\r\n".to_vec());
+        let request = crate::request::RequestReader::new(stream, 0).read_request().unwrap().unwrap();
+        let mut captures = super::HashMap::new();
+        captures.insert(String::from("id"), String::from("42"));
+
+        let response = super::fill_template(b"{\"id\": {{id}}, \"request\": \"{{ header.x-request-id }}\", \"other\": \"{{other}}\"}{{", &captures, &request);
+        assert_eq!(&b"{\"id\": 42, \"request\": \"abc\", \"other\": \"{{other}}\"}{{"[..], &response[..]);
+    }
+}
@@ -0,0 +1,1507 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{read_to_string, File};
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::*;
+#[cfg(unix)]
+use signal_hook::{consts::SIGHUP, iterator::{Handle, Signals}};
+
+use crate::admin;
+use crate::clock::Clock;
+use crate::idle::{self, Connection};
+use crate::watch;
+use crate::config::{
+    find_errors, find_missing_response_files, find_warnings, find_shadowed_mocks, parse_source, Command, ConfigError, Mock, Part, Profiles,
+    RequestText, Scenario, ANY_PROFILE, DEFAULT_PROFILE, INITIAL_STATE,
+};
+use crate::journal::{Journal, JournalEntry};
+use crate::proxy::{self, Upstream};
+use crate::record::Recorder;
+use crate::request::{Request, RequestError, RequestReader};
+use crate::response::{fill_template, is_delimited, set_content_length};
+use crate::session::SessionKey;
+
+pub const DEFAULT_WORKERS: usize = 64;
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 5000;
+pub const DEFAULT_MAX_BODY: usize = 10 * 1024 * 1024;
+pub const DEFAULT_JOURNAL_LIMIT: usize = 10000;
+pub const DEFAULT_RELOAD_INTERVAL: u64 = 1000;
+
+/// Settings that affect how requests are handled
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// number of connections served in parallel
+    pub workers: usize,
+    /// maximum size in bytes of a request body
+    pub max_body: usize,
+    /// how long to wait for the next request on a kept alive connection
+    pub idle_timeout: Duration,
+    /// compute the content-length header of all responses
+    pub content_length: bool,
+    /// directory the response files are relative to
+    pub base_dir: PathBuf,
+    /// number of requests kept in the journal, the oldest are dropped
+    pub journal_limit: usize,
+    /// how often to check the config and response files for changes,
+    /// `None` never reloads them. Only used when started from a file.
+    pub reload: Option<Duration>,
+    /// url of the server the requests no mock matches are forwarded to,
+    /// e.g. `http://localhost:9000/api`. Without it they get the 404 response.
+    pub upstream: Option<String>,
+    /// config file the exchanges with the upstream are appended to as mocks,
+    /// the response files are written in its directory
+    pub record: Option<PathBuf>,
+    /// request headers added to the criteria of the recorded mocks
+    pub record_headers: Vec<String>,
+    /// start with a virtual clock for the `after` mocks, it only moves when advanced
+    pub virtual_clock: bool,
+    /// keep a profile and timers per session, the session of a request is the value of
+    /// `header:NAME`, `cookie:NAME` or the client `ip`. `None` shares them between all the requests.
+    pub session: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            workers: DEFAULT_WORKERS,
+            max_body: DEFAULT_MAX_BODY,
+            idle_timeout: Duration::from_millis(DEFAULT_IDLE_TIMEOUT),
+            content_length: false,
+            base_dir: PathBuf::from("."),
+            journal_limit: DEFAULT_JOURNAL_LIMIT,
+            reload: None,
+            upstream: None,
+            record: None,
+            record_headers: Vec::new(),
+            virtual_clock: false,
+            session: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// the config file could not be parsed
+    Config(Vec<ConfigError>),
+    /// the config file failed one of the checks
+    Invalid(&'static str),
+    /// the options can't be used
+    Options(&'static str),
+    /// no mock uses a profile with this name
+    UnknownProfile(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Config(errors) => {
+                write!(f, "Invalid config file")?;
+                for e in errors {
+                    write!(f, "\n{}", e)?;
+                }
+                Ok(())
+            },
+            Error::Invalid(e) => write!(f, "Invalid config file, {}", e),
+            Error::Options(e) => write!(f, "Invalid options, {}", e),
+            Error::UnknownProfile(name) => write!(f, "Unknown profile {}", name),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// State changed by the mocks at runtime, one per session
+#[derive(Debug, Clone)]
+pub(crate) struct State {
+    /// time of the clock when all the timers were reset
+    pub(crate) time_origin: Duration,
+    /// time of the clock when a timer was reset on its own, `None` is the default timer
+    pub(crate) timers: HashMap<Option<String>, Duration>,
+    pub(crate) profile: isize,
+    /// number of matching requests of the mocks with `times` or `nth`, by mock id
+    pub(crate) hits: HashMap<usize, usize>,
+    /// state of the scenarios that left the initial one, by name
+    pub(crate) scenarios: HashMap<String, String>,
+}
+
+impl State {
+    /// The default profile with all the timers starting now
+    fn new(now: Duration) -> State {
+        State { time_origin: now, timers: HashMap::new(), profile: DEFAULT_PROFILE, hits: HashMap::new(), scenarios: HashMap::new() }
+    }
+
+    /// Time of the clock when the timer was last reset
+    pub(crate) fn origin(&self, timer: Option<&str>) -> Duration {
+        self.timers.get(&timer.map(String::from)).copied().unwrap_or(self.time_origin)
+    }
+
+    pub(crate) fn scenario_state(&self, name: &str) -> &str {
+        self.scenarios.get(name).map(String::as_str).unwrap_or(INITIAL_STATE)
+    }
+
+    /// Resets one timer, the others keep running. The default timer also resets the hit counters.
+    pub(crate) fn reset(&mut self, timer: Option<&str>, now: Duration) {
+        self.timers.insert(timer.map(String::from), now);
+        if timer.is_none() {
+            self.hits.clear();
+        }
+    }
+
+    /// Resets the default timer, all the named ones, the hit counters and the scenarios
+    pub(crate) fn reset_all(&mut self, now: Duration) {
+        self.time_origin = now;
+        self.timers.clear();
+        self.hits.clear();
+        self.scenarios.clear();
+    }
+}
+
+/// The state of the requests without a session and of each session, shared by all the workers.
+/// The sessions are kept until they are cleared, nothing expires them.
+#[derive(Debug)]
+pub(crate) struct Sessions {
+    shared: State,
+    sessions: HashMap<String, State>,
+}
+
+impl Sessions {
+    /// The state of the session to change, created the first time the session is seen
+    pub(crate) fn get_mut(&mut self, session: Option<&str>, now: Duration) -> &mut State {
+        match session {
+            Some(session) => self.sessions.entry(session.to_string()).or_insert_with(|| State::new(now)),
+            None => &mut self.shared,
+        }
+    }
+
+    /// The state of the session to read, a session not seen yet is not created and has a new state
+    pub(crate) fn get(&self, session: Option<&str>, now: Duration) -> Cow<'_, State> {
+        match session {
+            Some(session) => self.sessions.get(session).map(Cow::Borrowed).unwrap_or_else(|| Cow::Owned(State::new(now))),
+            None => Cow::Borrowed(&self.shared),
+        }
+    }
+
+    pub(crate) fn all_mut(&mut self) -> impl Iterator<Item = &mut State> {
+        std::iter::once(&mut self.shared).chain(self.sessions.values_mut())
+    }
+
+    /// The sessions seen so far with their state, sorted by id
+    pub(crate) fn sessions(&self) -> Vec<(&str, &State)> {
+        let mut sessions: Vec<(&str, &State)> = self.sessions.iter().map(|(id, state)| (id.as_str(), state)).collect();
+        sessions.sort_by_key(|(id, _)| *id);
+        sessions
+    }
+
+    /// Forgets the sessions, returns how many there were
+    pub(crate) fn clear(&mut self) -> usize {
+        let count = self.sessions.len();
+        self.sessions.clear();
+        count
+    }
+}
+
+/// Where to insert the mocks added at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    /// before all the other mocks, they are matched first
+    Front,
+    /// after all the other mocks
+    Back,
+    /// before the mock currently at this index
+    At(usize),
+}
+
+/// Everything the workers need to serve the requests
+pub(crate) struct Server {
+    /// replaced as a whole when mocks are added or removed,
+    /// the requests being served keep the list they started with
+    config: RwLock<Arc<Vec<Mock>>>,
+    profiles: Mutex<Profiles>,
+    /// id of the next mock loaded
+    next_id: AtomicUsize,
+    /// the file the config was read from, if any
+    config_file: Option<PathBuf>,
+    default_mock: Mock,
+    upstream: Option<Upstream>,
+    recorder: Option<Recorder>,
+    session_key: Option<SessionKey>,
+    pub(crate) state: Mutex<Sessions>,
+    pub(crate) clock: Clock,
+    /// number of requests received so far
+    counter: AtomicUsize,
+    pub(crate) journal: Journal,
+    options: Options,
+    pub(crate) shutdown: AtomicBool,
+}
+
+const RESPONSE404: &str = r##"HTTP/1.0 404 Not Found
+server: iron-mockside
+content-type: text/html
+
+<!DOCTYPE html>
+<html>
+  <head>
+    <style type="text/css">
+      body {
+        width: 35em;
+        margin: 0 auto;
+        font-family: "Lucida Console", Monaco, monospace;
+      }
+    </style>
+  </head>
+  <body>
+    <h1>R Tape loading error, 404</h1>
+  </body>
+</html>
+"##;
+
+/// Parses the config read from the file, if any, and runs all the checks on it.
+/// The includes of a config not read from a file are relative to the base directory.
+fn load(config: &str, file: Option<&Path>, base_dir: &Path) -> Result<(Vec<Mock>, Profiles), Error> {
+    let mut profiles = Profiles::new();
+    let config = parse_source(config, file, base_dir, &mut profiles).map_err(Error::Config)?;
+    info!("Parsed configuration:\n{:#?}", config);
+    // the same checks as the check subcommand
+    let errors = find_errors(&config, base_dir);
+    if !errors.is_empty() {
+        return Err(Error::Config(errors));
+    }
+    for warning in find_warnings(&config) {
+        warn!("{}", warning);
+    }
+    Ok((config, profiles))
+}
+
+impl Server {
+    /// The mocks currently loaded
+    pub(crate) fn mocks(&self) -> Arc<Vec<Mock>> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    pub(crate) fn profile_name(&self, id: isize) -> String {
+        self.profiles.lock().unwrap().name(id).map(String::from).unwrap_or_else(|| id.to_string())
+    }
+
+    /// The session of the request, `None` without sessions
+    pub(crate) fn session(&self, request: &Request, client: Option<IpAddr>) -> Option<String> {
+        self.session_key.as_ref().and_then(|key| key.session(request, client))
+    }
+
+    pub(crate) fn profile(&self, session: Option<&str>) -> String {
+        let profile = self.state.lock().unwrap().get(session, self.clock.now()).profile;
+        self.profile_name(profile)
+    }
+
+    pub(crate) fn set_profile(&self, session: Option<&str>, name: &str) -> Result<(), Error> {
+        let id = self.profiles.lock().unwrap().id(name);
+        match id {
+            Some(id) if id != ANY_PROFILE => {
+                let mut sessions = self.state.lock().unwrap();
+                let state = sessions.get_mut(session, self.clock.now());
+                info!("Switched to profile {} from {}", name, self.profile_name(state.profile));
+                state.profile = id;
+                Ok(())
+            },
+            _ => Err(Error::UnknownProfile(name.to_string())),
+        }
+    }
+
+    /// Parses the mocks and inserts them, returns their ids.
+    /// Nothing changes if they fail the checks done when the server starts.
+    pub(crate) fn add_mocks(&self, config: &str, position: Position) -> Result<Vec<usize>, Error> {
+        // held until the profiles are written back, concurrent additions would get the same profile ids
+        let mut current = self.config.write().unwrap();
+        let mut profiles = self.profiles.lock().unwrap().clone();
+        let mut mocks = parse_source(config, None, &self.options.base_dir, &mut profiles).map_err(Error::Config)?;
+        let errors = find_missing_response_files(&mocks, &self.options.base_dir);
+        if !errors.is_empty() {
+            return Err(Error::Config(errors));
+        }
+        for mock in mocks.iter_mut() {
+            mock.id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        }
+        let ids: Vec<usize> = mocks.iter().map(|m| m.id).collect();
+
+        let index = match position {
+            Position::Front => 0,
+            Position::Back => current.len(),
+            Position::At(index) if index <= current.len() => index,
+            Position::At(_) => return Err(Error::Invalid("the position is past the end of the mocks")),
+        };
+        let mut config = current.as_ref().clone();
+        config.splice(index..index, mocks);
+        let errors = find_shadowed_mocks(&config);
+        if !errors.is_empty() {
+            return Err(Error::Config(errors));
+        }
+        info!("Added mocks {:?} at position {}", ids, index);
+        *current = Arc::new(config);
+        *self.profiles.lock().unwrap() = profiles;
+        Ok(ids)
+    }
+
+    /// Reads the config file again and replaces all the mocks with its content.
+    /// The timer is kept, so is the profile if it still exists.
+    pub(crate) fn reload(&self) -> Result<(), Error> {
+        let config_file = self.config_file.as_ref().ok_or(Error::Options("the server was not started from a config file"))?;
+        let (mut config, profiles) = load(&read_to_string(config_file)?, Some(config_file), &self.options.base_dir)?;
+        for mock in config.iter_mut() {
+            mock.id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let mut current = self.config.write().unwrap();
+        let mut sessions = self.state.lock().unwrap();
+        for state in sessions.all_mut() {
+            // the counters are by mock id, the reloaded mocks have new ones
+            state.hits.clear();
+            let name = self.profile_name(state.profile);
+            state.profile = match profiles.id(&name) {
+                Some(id) if id != ANY_PROFILE => id,
+                _ => {
+                    warn!("Profile {} no longer exists, switching to the default one", name);
+                    DEFAULT_PROFILE
+                },
+            };
+        }
+        *self.profiles.lock().unwrap() = profiles;
+        *current = Arc::new(config);
+        info!("Reloaded {}", config_file.display());
+        Ok(())
+    }
+
+    /// The files a change in should reload the config: the config files and the response files
+    pub(crate) fn watched_files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = self.config_file.iter().cloned().collect();
+        for mock in self.mocks().iter() {
+            if let Some(file) = &mock.file {
+                if !files.contains(file) {
+                    files.push(file.clone());
+                }
+            }
+            for file in mock.response_files() {
+                let file = mock.response_path(&self.options.base_dir, file);
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        files
+    }
+
+    pub(crate) fn reset_timer(&self, session: Option<&str>) {
+        let now = self.clock.now();
+        self.state.lock().unwrap().get_mut(session, now).reset_all(now);
+    }
+
+    pub(crate) fn reset_named_timer(&self, session: Option<&str>, name: &str) {
+        let now = self.clock.now();
+        self.state.lock().unwrap().get_mut(session, now).reset(Some(name), now);
+    }
+
+    pub(crate) fn advance_clock(&self, duration: Duration) -> Result<(), Error> {
+        if self.clock.advance(duration) {
+            info!("Clock advanced by {} ms", duration.as_millis());
+            Ok(())
+        } else {
+            Err(Error::Options("the clock is not virtual"))
+        }
+    }
+
+    /// Removes the mock with this id, returns false if there is none
+    pub(crate) fn remove_mock(&self, id: usize) -> bool {
+        let mut current = self.config.write().unwrap();
+        if !current.iter().any(|m| m.id == id) {
+            return false;
+        }
+        let config = current.iter().filter(|m| m.id != id).cloned().collect();
+        *current = Arc::new(config);
+        info!("Removed mock {}", id);
+        true
+    }
+}
+
+const RESPONSE500: &str = "HTTP/1.1 500 Internal Server Error\r\nserver: iron-mockside\r\ncontent-length: 0\r\n\r\n";
+const RESPONSE502: &str = "HTTP/1.1 502 Bad Gateway\r\nserver: iron-mockside\r\ncontent-length: 0\r\n\r\n";
+
+/// A running mock server, it stops when dropped.
+pub struct MockServer {
+    address: SocketAddr,
+    server: Arc<Server>,
+    acceptor: Option<thread::JoinHandle<()>>,
+    /// stops the SIGHUP handler
+    #[cfg(unix)]
+    hangup: Option<Handle>,
+}
+
+impl MockServer {
+    /// Parses and checks the configuration, then starts serving it.
+    /// Use port 0 to listen on a port chosen by the system, see [`MockServer::address`].
+    pub fn start<A: ToSocketAddrs>(address: A, config: &str, options: Options) -> Result<MockServer, Error> {
+        MockServer::start_with(address, config, options, None)
+    }
+
+    fn start_with<A: ToSocketAddrs>(address: A, config: &str, options: Options, config_file: Option<PathBuf>) -> Result<MockServer, Error> {
+        if options.workers == 0 {
+            return Err(Error::Options("the number of workers must be at least 1"));
+        }
+        if options.idle_timeout.as_millis() == 0 {
+            return Err(Error::Options("the idle timeout must be at least 1 millisecond"));
+        }
+
+        let upstream = options.upstream.as_deref().map(Upstream::parse).transpose().map_err(Error::Options)?;
+        if options.record.is_some() && upstream.is_none() {
+            return Err(Error::Options("recording needs an upstream"));
+        }
+        let recorder = options.record.clone().map(|file| Recorder::new(file, options.record_headers.clone()));
+        let session_key = options.session.as_deref().map(SessionKey::parse).transpose().map_err(Error::Options)?;
+        let (mut config, profiles) = load(config, config_file.as_deref(), &options.base_dir)?;
+
+        let default_mock = Mock {
+            filenames: String::from("404.html"),
+            patterns: Vec::new(),
+            time: None,
+            timer: None,
+            delay: None,
+            profile: -1,
+            destination_profile: ANY_PROFILE,
+            command: Command::Serve,
+            // without a length the connection would be closed after every unmatched request
+            content_length: true,
+            template: false,
+            times: None,
+            nth: None,
+            scenario: None,
+            line_number: 0,
+            file: None,
+            response_dir: None,
+            id: 0,
+        };
+        for (n, mock) in config.iter_mut().enumerate() {
+            mock.id = n + 1;
+        }
+
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+
+        let server = Arc::new(Server {
+            next_id: AtomicUsize::new(config.len() + 1),
+            config: RwLock::new(Arc::new(config)),
+            profiles: Mutex::new(profiles),
+            config_file,
+            default_mock,
+            upstream,
+            recorder,
+            session_key,
+            state: Mutex::new(Sessions {
+                shared: State::new(Duration::from_millis(0)),
+                sessions: HashMap::new(),
+            }),
+            clock: Clock::new(options.virtual_clock),
+            counter: AtomicUsize::new(0),
+            journal: Journal::new(options.journal_limit),
+            options,
+            shutdown: AtomicBool::new(false),
+        });
+
+        // the workers pick the connections with a request from a shared queue,
+        // a delayed response only blocks the worker serving it. Between the requests
+        // the connections wait in the idle watcher, they don't hold a worker.
+        let (sender, receiver) = mpsc::channel::<Connection>();
+        let (idle_sender, idle_receiver) = mpsc::channel::<Connection>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for n in 0..server.options.workers {
+            let server = Arc::clone(&server);
+            let receiver = Arc::clone(&receiver);
+            let idle_sender = idle_sender.clone();
+            thread::Builder::new().name(format!("worker-{}", n)).spawn(move || loop {
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    // a panic only loses its connection, the worker keeps serving
+                    Ok(connection) => match panic::catch_unwind(AssertUnwindSafe(|| handle_connection(connection, &server))) {
+                        Ok(Some(connection)) => {
+                            let _ = idle_sender.send(connection);
+                        },
+                        Ok(None) => (),
+                        Err(_) => error!("Panicked while serving a connection"),
+                    },
+                    Err(_) => break,
+                }
+            })?;
+        }
+        {
+            let server = Arc::clone(&server);
+            thread::Builder::new().name(String::from("idle")).spawn(move || {
+                // dropping the sender at shutdown stops the workers
+                idle::watch(idle_receiver, sender, server.options.idle_timeout, &server.shutdown)
+            })?;
+        }
+
+        if let (Some(interval), Some(_)) = (server.options.reload, &server.config_file) {
+            let server = Arc::clone(&server);
+            thread::Builder::new().name(String::from("watcher")).spawn(move || watch::watch(&server, interval))?;
+        }
+
+        let acceptor = {
+            let server = Arc::clone(&server);
+            thread::Builder::new().name(String::from("acceptor")).spawn(move || {
+                for stream in listener.incoming() {
+                    if server.shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            println!("Incoming connection");
+                            // a request that stops in the middle doesn't hold its worker forever
+                            if let Err(e) = stream.set_read_timeout(Some(server.options.idle_timeout)) {
+                                error!("Failed to set the idle timeout: {}", e);
+                            }
+                            let client = stream.peer_addr().ok().map(|a| a.ip());
+                            let connection = Connection::new(RequestReader::new(stream, server.options.max_body), client);
+                            if let Err(e) = idle_sender.send(connection) {
+                                error!("No worker left to serve the connections: {}", e);
+                                break;
+                            }
+                        },
+                        Err(e) => error!("Failed to accept connection: {}", e),
+                    }
+                }
+            })?
+        };
+
+        Ok(MockServer {
+            address,
+            server,
+            acceptor: Some(acceptor),
+            #[cfg(unix)]
+            hangup: None,
+        })
+    }
+
+    /// Reads the configuration from a file, the response files are relative to its directory
+    pub fn start_file<A: ToSocketAddrs, P: AsRef<Path>>(address: A, config_file: P, mut options: Options) -> Result<MockServer, Error> {
+        let config_file = config_file.as_ref();
+        let config = read_to_string(config_file)?;
+        options.base_dir = config_file.parent().map(Path::to_path_buf).unwrap_or_default();
+        MockServer::start_with(address, &config, options, Some(config_file.to_path_buf()))
+    }
+
+    /// The address the server listens to
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Base url of the server, e.g. `http://127.0.0.1:8080`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// All the requests received so far, in order
+    pub fn requests(&self) -> Vec<JournalEntry> {
+        self.server.journal.find("")
+    }
+
+    /// The requests containing the text, e.g. `POST /payments`
+    pub fn find_requests(&self, text: &str) -> Vec<JournalEntry> {
+        self.server.journal.find(text)
+    }
+
+    /// Number of requests containing the text
+    pub fn count_requests(&self, text: &str) -> usize {
+        self.server.journal.count(text)
+    }
+
+    /// Forgets the requests received so far
+    pub fn clear_requests(&self) {
+        self.server.journal.clear();
+    }
+
+    /// Name of the current profile of the requests without a session
+    pub fn profile(&self) -> String {
+        self.server.profile(None)
+    }
+
+    /// Switches to another profile, like a `profile [name]` mock would
+    pub fn set_profile(&self, name: &str) -> Result<(), Error> {
+        self.server.set_profile(None, name)
+    }
+
+    /// Name of the current profile of a session
+    pub fn session_profile(&self, session: &str) -> String {
+        self.server.profile(Some(session))
+    }
+
+    /// Switches a session to another profile
+    pub fn set_session_profile(&self, session: &str, name: &str) -> Result<(), Error> {
+        self.server.set_profile(Some(session), name)
+    }
+
+    /// State of a scenario for the requests without a session
+    pub fn scenario_state(&self, name: &str) -> String {
+        let now = self.server.clock.now();
+        self.server.state.lock().unwrap().get(None, now).scenario_state(name).to_string()
+    }
+
+    /// Forgets the profiles and timers of all the sessions
+    pub fn clear_sessions(&self) {
+        self.server.state.lock().unwrap().clear();
+    }
+
+    /// Sets the default timer and all the named ones of the `after` mocks to now
+    pub fn reset_timer(&self) {
+        self.server.reset_timer(None);
+    }
+
+    /// Sets one named timer to now, like a `reset [name]` mock would
+    pub fn reset_named_timer(&self, name: &str) {
+        self.server.reset_named_timer(None, name);
+    }
+
+    /// Switches between the real clock and a virtual one that only moves when advanced
+    pub fn set_virtual_clock(&self, virtual_clock: bool) {
+        self.server.clock.set_virtual(virtual_clock);
+    }
+
+    /// Moves the virtual clock forward, the `after` mocks see the time passing
+    pub fn advance_clock(&self, duration: Duration) -> Result<(), Error> {
+        self.server.advance_clock(duration)
+    }
+
+    /// Adds mocks written like in the config file, returns their ids.
+    /// The response files are relative to the base directory of the options.
+    pub fn add_mocks(&self, config: &str, position: Position) -> Result<Vec<usize>, Error> {
+        self.server.add_mocks(config, position)
+    }
+
+    /// Removes a mock, returns false if there is no mock with this id
+    pub fn remove_mock(&self, id: usize) -> bool {
+        self.server.remove_mock(id)
+    }
+
+    /// Reads the config file again, the mocks added at runtime are dropped.
+    /// Nothing changes if the new config fails the checks.
+    pub fn reload(&self) -> Result<(), Error> {
+        self.server.reload()
+    }
+
+    /// Reloads the config file every time the process receives SIGHUP,
+    /// like [`MockServer::reload`]. Useful where the changes of the files are not seen.
+    #[cfg(unix)]
+    pub fn reload_on_hangup(&mut self) -> Result<(), Error> {
+        let mut signals = Signals::new([SIGHUP])?;
+        self.hangup = Some(signals.handle());
+        let server = Arc::clone(&self.server);
+        thread::Builder::new().name(String::from("hangup")).spawn(move || {
+            for _ in signals.forever() {
+                info!("SIGHUP received, reloading the config");
+                if let Err(e) = server.reload() {
+                    error!("Keeping the current config, {}", e);
+                }
+            }
+        })?;
+        Ok(())
+    }
+
+    /// Blocks the current thread while the server is running
+    pub fn wait(mut self) {
+        if let Some(acceptor) = self.acceptor.take() {
+            let _ = acceptor.join();
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.server.shutdown.store(true, Ordering::SeqCst);
+        #[cfg(unix)]
+        if let Some(hangup) = self.hangup.take() {
+            hangup.close();
+        }
+        if let Some(acceptor) = self.acceptor.take() {
+            // a connection wakes up the acceptor so it can see the shutdown flag
+            let mut address = self.address;
+            if address.ip().is_unspecified() {
+                address.set_ip(match address.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            if TcpStream::connect(address).is_ok() {
+                let _ = acceptor.join();
+            }
+        }
+    }
+}
+
+/// Serves the requests received on a connection, returns the connection to keep
+/// once there is no request left to serve, `None` if it is closed
+fn handle_connection(mut connection: Connection, server: &Server) -> Option<Connection> {
+    let client = connection.client;
+    loop {
+        let reader = &mut connection.reader;
+        let request = match reader.read_request() {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                debug!("Connection closed by the client");
+                return None;
+            },
+            Err(RequestError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                debug!("Closing connection stalled in the middle of a request");
+                return None;
+            },
+            Err(e) => {
+                error!("Failed to read request: {}", e);
+                if let Some(response) = e.response() {
+                    let _ = reader.get_mut().write_all(response);
+                }
+                return None;
+            }
+        };
+        let counter = server.counter.fetch_add(1, Ordering::SeqCst) + 1;
+        debug!("Request {}: {} {} {}, {} bytes of body", counter, request.method, request.target, request.version, request.body.len());
+        let response = handle_request(&request, client, server, counter);
+        let stream = reader.get_mut();
+        if let Err(e) = stream.write_all(&response).and_then(|_| stream.flush()) {
+            error!("Failed to send response {}: {}", counter, e);
+            return None;
+        }
+        if !request.keep_alive() || server.shutdown.load(Ordering::SeqCst) {
+            return None;
+        }
+        if !is_delimited(&response) {
+            debug!("Response {} has no length, closing the connection to mark its end", counter);
+            return None;
+        }
+        // the pipelined requests are served right away
+        if !reader.is_buffered() {
+            connection.idle_since = Instant::now();
+            return Some(connection);
+        }
+    }
+}
+
+/// Finds the mock for the request, applies its commands and returns the response
+fn handle_request(request: &Request, client: Option<IpAddr>, server: &Server, counter: usize) -> Vec<u8> {
+    let session = server.session(request, client);
+    if admin::is_admin(&request.target) {
+        return admin::handle(request, session.as_deref(), server);
+    }
+    let options = &server.options;
+    let text = request.text();
+    // the wire form is only different for a chunked body
+    let wire_text = request.wire_body.as_ref().map(|_| request.wire_text());
+    let request_text = RequestText { decoded: &text, wire: wire_text.as_deref().unwrap_or(&text) };
+    let config = server.mocks();
+
+    // the lookup and the state change have to be atomic, otherwise a concurrent
+    // request could see the profile of the previous one
+    let now = server.clock.now();
+    let mut sessions = server.state.lock().unwrap();
+    let state = sessions.get_mut(session.as_deref(), now);
+    let mut mock_found = false;
+    let upstream = server.upstream.as_ref();
+    let mock = match find_mock(request_text, &config, now, &mut *state) {
+        Some(mock) => { mock_found = true; mock},
+        None => &server.default_mock,
+    };
+
+    let current_profile = state.profile;
+    match mock.command {
+        Command::Reset => state.reset(mock.timer.as_deref(), now),
+        Command::Profile => state.profile = mock.destination_profile,
+        _ => ()
+    }
+    if let Some(Scenario { name, next: Some(next), .. }) = &mock.scenario {
+        info!("Scenario {} moved to [{}] from [{}]", name, next, state.scenario_state(name));
+        state.scenarios.insert(name.clone(), next.clone());
+    }
+    drop(sessions);
+
+    server.journal.record(JournalEntry {
+        request: text.clone(),
+        method: request.method.clone(),
+        target: request.target.clone(),
+        time: SystemTime::now(),
+        line_number: if mock_found { Some(mock.line_number) } else { None },
+        // the ids of the profiles change on reload
+        profile: server.profile_name(current_profile),
+        session,
+    });
+
+    {
+        // keep the output of one request together
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        if mock_found {
+            if counter.is_multiple_of(2) {
+                // light green
+                write!(out, "\x1b[32;1m").unwrap();
+            } else {
+                // green
+                write!(out, "\x1b[32m").unwrap();
+            }
+        } else {
+            // "\x1B[31;1;4m" red, bold, underligned
+            write!(out, "\x1B[31;1m").unwrap();
+        }
+        writeln!(out, "=========================\nRequest {}:\n{}\n\n", counter, text).unwrap();
+        info!("Current profile {}\n, mock: {:#?}", current_profile, mock);
+        match upstream {
+            Some(upstream) if !mock_found => writeln!(out, "Response: forwarded to {}", upstream.url).unwrap(),
+            _ => writeln!(out, "Response: {}", mock.filenames).unwrap(),
+        }
+        if mock.command == Command::Profile {
+            writeln!(out, "Switched to profile {} from {}", mock.destination_profile, current_profile).unwrap();
+        }
+        // Reset the colors
+        write!(out, "\x1B[0m").unwrap();
+    }
+    if let (Some(upstream), false) = (upstream, mock_found) {
+        return match proxy::forward(request, upstream) {
+            Ok(response) => {
+                if let Some(recorder) = &server.recorder {
+                    if let Err(e) = recorder.record(request, &response) {
+                        error!("Failed to record request {}: {}", counter, e);
+                    }
+                }
+                response
+            },
+            Err(e) => {
+                error!("Failed to forward request {} to {}: {}", counter, upstream.url, e);
+                RESPONSE502.as_bytes().to_vec()
+            },
+        };
+    }
+    if mock.command == Command::Delay {
+        thread::sleep(mock.delay.unwrap());
+    }
+
+    let mut captures = HashMap::new();
+    if mock.template {
+        for pattern in &mock.patterns {
+            pattern.captures(request_text, &mut captures);
+        }
+    }
+    let mut response = Vec::new();
+    for part in mock.response_parts() {
+        let file = match part {
+            Part::Text(text) if mock.template => {
+                response.extend(fill_template(text.as_bytes(), &captures, request));
+                continue;
+            },
+            Part::Text(text) => {
+                response.extend_from_slice(text.as_bytes());
+                continue;
+            },
+            Part::File(file) => file,
+        };
+        match File::open(mock.response_path(&options.base_dir, file)) {
+            Ok(mut from_file) => {
+                let mut content = Vec::new();
+                // a directory can be opened but not read
+                if let Err(e) = from_file.read_to_end(&mut content) {
+                    error!("Could not read file {} to service request, error {}", file, e);
+                    return RESPONSE500.as_bytes().to_vec();
+                }
+                if mock.template {
+                    content = fill_template(&content, &captures, request);
+                }
+                response.extend(content);
+            },
+            Err(e) => {
+                if mock.patterns.is_empty() {
+                    debug!("No file specified for 404, responding with default");
+                    response.extend_from_slice(RESPONSE404.as_bytes());
+                } else {
+                    error!("Could not open file {} to service request, error {}", file, e);
+                    return RESPONSE500.as_bytes().to_vec();
+                }
+            }
+        }
+    }
+
+    if options.content_length || mock.content_length {
+        response = set_content_length(&response);
+    }
+    response
+}
+
+/// Finds a mock in the configuration corresponding to this request,
+/// counts the hits of the mocks with `times` or `nth` it goes through
+fn find_mock<'b>(
+    request: RequestText,
+    config: &'b [Mock],
+    now: Duration,
+    state: &mut State,
+) -> Option<&'b Mock> {
+    'outside: for mock in config {
+        if mock.profile != ANY_PROFILE && state.profile != mock.profile {
+                continue 'outside;
+        }
+        for pattern in &mock.patterns {
+            if !pattern.is_met(request) {
+                continue 'outside;
+            }
+        }
+        if let Some(duration) = mock.time {
+            if now.saturating_sub(state.origin(mock.timer.as_deref())) < duration {
+                continue 'outside;
+            }
+        }
+        if let Some(Scenario { name, state: Some(required), .. }) = &mock.scenario {
+            if state.scenario_state(name) != required {
+                continue 'outside;
+            }
+        }
+        if let Some(times) = mock.times {
+            let hits = state.hits.entry(mock.id).or_insert(0);
+            if *hits >= times {
+                continue 'outside;
+            }
+            *hits += 1;
+        }
+        if let Some(nth) = mock.nth {
+            let hits = state.hits.entry(mock.id).or_insert(0);
+            *hits += 1;
+            if *hits != nth {
+                continue 'outside;
+            }
+        }
+
+        return Some(mock);
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::test_dir::TestDir;
+
+    /// Options serving the response files of the mocks directory
+    fn mocks_options() -> super::Options {
+        super::Options { base_dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("mocks"), ..Default::default() }
+    }
+
+    fn get(server: &super::MockServer, path: &str) -> String {
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn send(server: &super::MockServer, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        write!(stream, "{} {} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn put(server: &super::MockServer, path: &str, body: &str) -> String {
+        send(server, "PUT", path, body)
+    }
+
+    fn post(server: &super::MockServer, path: &str, body: &str) -> String {
+        send(server, "POST", path, body)
+    }
+
+    fn delete(server: &super::MockServer, path: &str) -> String {
+        send(server, "DELETE", path, "")
+    }
+
+    #[test]
+    fn serve_config_file() {
+        let config_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("mocks").join("config.txt");
+        let server = super::MockServer::start_file("127.0.0.1:0", config_file, super::Options::default()).unwrap();
+        assert_ne!(0, server.address().port());
+
+        assert!(get(&server, "/hello").starts_with("HTTP/1.1 200 OK"));
+        assert!(get(&server, "/missing").starts_with("HTTP/1.1 404 Not Found"));
+
+        let address = server.address();
+        drop(server);
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn pipelined_not_found() {
+        let config = "/hello\n`content-length;\"HTTP/1.1 200 OK\\r\\n\\r\\nhi\"\n";
+        let server = super::MockServer::start("127.0.0.1:0", config, super::Options::default()).unwrap();
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        write!(stream, "GET /hello HTTP/1.1\r\n\r\nGET /missing HTTP/1.1\r\n\r\nGET /hello HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        // all three answered in order on the same connection
+        let not_found = response.find("HTTP/1.0 404 Not Found").unwrap();
+        assert!(response[..not_found].ends_with("hi"), "{}", response);
+        assert!(response[not_found..].contains("</html>\nHTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("hi"));
+    }
+
+    #[test]
+    fn idle_connections_dont_hold_workers() {
+        let config = "/hello\n`content-length;\"HTTP/1.1 200 OK\\r\\n\\r\\nhi\"\n";
+        let options = super::Options { workers: 1, ..Default::default() };
+        let server = super::MockServer::start("127.0.0.1:0", config, options).unwrap();
+        // kept alive after their first response
+        let mut idle: Vec<TcpStream> = (0..3).map(|_| {
+            let mut stream = TcpStream::connect(server.address()).unwrap();
+            write!(stream, "GET /hello HTTP/1.1\r\n\r\n").unwrap();
+            let mut buffer = [0u8; 256];
+            let count = stream.read(&mut buffer).unwrap();
+            assert!(buffer[..count].ends_with(b"hi"));
+            stream
+        }).collect();
+
+        let start = std::time::Instant::now();
+        assert!(get(&server, "/hello").ends_with("hi"));
+        assert!(start.elapsed() < Duration::from_millis(1000), "waited {:?}", start.elapsed());
+
+        // the idle connections are still served
+        write!(idle[0], "GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        idle[0].read_to_string(&mut response).unwrap();
+        assert!(response.ends_with("hi"));
+    }
+
+    #[test]
+    fn journal() {
+        let config_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("mocks").join("config.txt");
+        let server = super::MockServer::start_file("127.0.0.1:0", config_file, super::Options::default()).unwrap();
+        get(&server, "/hello");
+        get(&server, "/hello?again");
+        get(&server, "/missing");
+
+        assert_eq!(3, server.requests().len());
+        assert_eq!(2, server.count_requests("GET /hello"));
+        let missing = server.find_requests("/missing");
+        assert_eq!(None, missing[0].line_number);
+        assert_eq!("GET", missing[0].method);
+
+        let count = get(&server, "/__mockside/requests/count?contains=GET%20%2Fhello");
+        assert!(count.ends_with("{\"count\":2}"), "{}", count);
+        get(&server, "/__mockside/requests/count");
+        assert_eq!(3, server.requests().len(), "admin requests are not recorded");
+
+        server.clear_requests();
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn admin_profile() {
+        let config = "/pro1\n`[profile];headers;hello.html\n\n/switch\n`profile [profile];headers;hello.html\n";
+        let options = mocks_options();
+        let server = super::MockServer::start("127.0.0.1:0", config, options).unwrap();
+        assert!(get(&server, "/pro1").starts_with("HTTP/1.1 404"));
+
+        let response = put(&server, "/__mockside/profile", "profile");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert_eq!("profile", server.profile());
+        assert!(get(&server, "/pro1").starts_with("HTTP/1.1 200"));
+        assert!(get(&server, "/__mockside/profile").ends_with("{\"profile\":\"profile\"}"));
+
+        assert!(put(&server, "/__mockside/profile", "unknown").starts_with("HTTP/1.1 404"));
+        server.set_profile("default").unwrap();
+        assert!(get(&server, "/pro1").starts_with("HTTP/1.1 404"));
+
+        let mocks = get(&server, "/__mockside/mocks");
+        assert!(mocks.contains("\"criteria\":[\"/switch\"]"), "{}", mocks);
+    }
+
+    #[test]
+    fn add_and_remove_mocks() {
+        let options = mocks_options();
+        let server = super::MockServer::start("127.0.0.1:0", "/hello\nheaders;hello.html\n", options).unwrap();
+        assert!(get(&server, "/stub").starts_with("HTTP/1.1 404"));
+
+        let ids = server.add_mocks("/stub\nheaders;hello.html\n", super::Position::Front).unwrap();
+        assert!(get(&server, "/stub").starts_with("HTTP/1.1 200"));
+        assert!(matches!(server.add_mocks("/stub/more\nheaders;hello.html\n", super::Position::Back), Err(super::Error::Config(_))));
+        assert!(matches!(server.add_mocks("/other\nheaders;missing.html\n", super::Position::Back), Err(super::Error::Config(_))));
+        assert!(matches!(server.add_mocks("/other\nheaders;hello.html\n", super::Position::At(3)), Err(super::Error::Invalid(_))));
+
+        assert!(server.remove_mock(ids[0]));
+        assert!(!server.remove_mock(ids[0]));
+        assert!(get(&server, "/stub").starts_with("HTTP/1.1 404"));
+
+        let response = post(&server, "/__mockside/mocks?position=1", "/posted\n`[other];headers;hello.html\n");
+        assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+        server.set_profile("other").unwrap();
+        assert!(get(&server, "/posted").starts_with("HTTP/1.1 200"));
+        let mocks = get(&server, "/__mockside/mocks");
+        assert!(mocks.contains("\"criteria\":[\"/posted\"]"), "{}", mocks);
+
+        let id = response.rsplit(&['[', ']'][..]).nth(1).unwrap();
+        assert!(delete(&server, &format!("/__mockside/mocks/{}", id)).starts_with("HTTP/1.1 200"));
+        assert!(delete(&server, &format!("/__mockside/mocks/{}", id)).starts_with("HTTP/1.1 404"));
+        assert!(post(&server, "/__mockside/mocks", "/bad\n`later;hello.html\n").starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn add_mocks_concurrently() {
+        let options = mocks_options();
+        let server = super::MockServer::start("127.0.0.1:0", "/hello\nheaders;hello.html\n", options).unwrap();
+        thread::scope(|scope| {
+            for n in 0..8 {
+                let server = &server;
+                scope.spawn(move || server.add_mocks(&format!("/p{}\n`[p{}];headers\n", n, n), super::Position::Back).unwrap());
+            }
+        });
+        for mock in server.server.mocks().iter().skip(1) {
+            let criteria = mock.patterns[0].to_string();
+            assert_eq!(criteria[1..], server.server.profile_name(mock.profile));
+        }
+    }
+
+    #[test]
+    fn reload_on_change() {
+        let dir = TestDir::new("reload");
+        std::fs::write(dir.join("headers"), "HTTP/1.1 200 OK\r\n\r\n").unwrap();
+        std::fs::write(dir.join("first"), "first").unwrap();
+        std::fs::write(dir.join("config.txt"), "/switch\n`profile [p];headers;first\n\n/page\n`[p];headers;first\n").unwrap();
+        let options = super::Options { reload: Some(Duration::from_millis(10)), ..Default::default() };
+        let server = super::MockServer::start_file("127.0.0.1:0", dir.join("config.txt"), options).unwrap();
+        get(&server, "/switch");
+        assert!(get(&server, "/page").ends_with("first"));
+
+        let wait_for = |body: &str| {
+            for _ in 0..500 {
+                if get(&server, "/page").ends_with(body) {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            false
+        };
+        // a new response file and a config referencing it
+        std::fs::write(dir.join("other"), "second").unwrap();
+        std::fs::write(dir.join("config.txt"), "/switch\n`profile [p];headers;first\n\n/page\n`[p];headers;other\n").unwrap();
+        assert!(wait_for("second"), "config not reloaded");
+        assert_eq!("p", server.profile());
+
+        // the response files are read for every request, still a change reloads the config
+        std::fs::write(dir.join("other"), "third one").unwrap();
+        assert!(wait_for("third one"));
+
+        // an invalid config is not loaded
+        std::fs::write(dir.join("config.txt"), "/page\nheaders;missing\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(get(&server, "/page").ends_with("third one"));
+        assert!(server.reload().is_err());
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reload_on_hangup() {
+        let dir = TestDir::new("hangup");
+        std::fs::write(dir.join("headers"), "HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n").unwrap();
+        std::fs::write(dir.join("first"), "first").unwrap();
+        std::fs::write(dir.join("other"), "other").unwrap();
+        std::fs::write(dir.join("config.txt"), "/page\nheaders;first\n").unwrap();
+        let mut server = super::MockServer::start_file("127.0.0.1:0", dir.join("config.txt"), super::Options::default()).unwrap();
+        server.reload_on_hangup().unwrap();
+
+        std::fs::write(dir.join("config.txt"), "/page\nheaders;other\n").unwrap();
+        assert!(get(&server, "/page").ends_with("first"), "no reload without the signal");
+        signal_hook::low_level::raise(signal_hook::consts::SIGHUP).unwrap();
+        let reloaded = (0..500).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            get(&server, "/page").ends_with("other")
+        });
+        assert!(reloaded, "config not reloaded");
+
+    }
+
+    #[test]
+    fn forward_to_upstream() {
+        let backend = super::MockServer::start("127.0.0.1:0", "/api/real\nheaders;hello.html\n", mocks_options()).unwrap();
+
+        let options = super::Options { upstream: Some(format!("{}/api", backend.url())), ..mocks_options() };
+        let server = super::MockServer::start("127.0.0.1:0", "/mocked\nheaders;404.html\n", options).unwrap();
+        assert!(get(&server, "/mocked").contains("404"));
+        let response = get(&server, "/real?q=1");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert_eq!(1, backend.count_requests("GET /api/real?q=1 HTTP/1.1"));
+        assert_eq!(None, server.find_requests("/real")[0].line_number);
+        assert!(get(&server, "/missing").starts_with("HTTP/1.1 404 Not Found"), "the upstream 404 is relayed");
+
+        let address = backend.address();
+        drop(backend);
+        let options = super::Options { upstream: Some(format!("http://{}", address)), ..mocks_options() };
+        let server = super::MockServer::start("127.0.0.1:0", "/mocked\nheaders;404.html\n", options).unwrap();
+        assert!(get(&server, "/real").starts_with("HTTP/1.1 502"));
+
+        let options = super::Options { upstream: Some(String::from("https://example.com")), ..mocks_options() };
+        assert!(matches!(super::MockServer::start("127.0.0.1:0", "", options), Err(super::Error::Options(_))));
+    }
+
+    #[test]
+    fn record() {
+        let dir = TestDir::new("record");
+        std::fs::write(dir.join("config.txt"), "# recorded").unwrap();
+        let backend = super::MockServer::start("127.0.0.1:0", "/hello\nheaders;hello.html\n", mocks_options()).unwrap();
+
+        let options = super::Options {
+            upstream: Some(backend.url()),
+            record: Some(dir.join("config.txt")),
+            record_headers: vec![String::from("Connection")],
+            ..Default::default()
+        };
+        let server = super::MockServer::start("127.0.0.1:0", "", options).unwrap();
+        let hello = get(&server, "/hello");
+        get(&server, "/hello");
+        let missing = get(&server, "/missing");
+        assert_eq!(
+            "# recorded\n\nGET /hello HTTP/\nConnection: close\n`content-length;get-hello-1.headers;get-hello-1.body\n\n\
+             GET /missing HTTP/\nConnection: close\n`content-length;get-missing-1.headers;get-missing-1.body\n",
+            std::fs::read_to_string(dir.join("config.txt")).unwrap()
+        );
+
+        // the recorded config serves the same responses
+        let replay = super::MockServer::start_file("127.0.0.1:0", dir.join("config.txt"), super::Options::default()).unwrap();
+        let body = |response: &str| response.split_once("\n\n").or_else(|| response.split_once("\r\n\r\n")).unwrap().1.to_string();
+        let replayed = get(&replay, "/hello");
+        assert!(replayed.starts_with("HTTP/1.1 200 OK\r\ncontent-length: 444\r\n"), "{}", replayed);
+        assert_eq!(body(&hello), body(&replayed));
+        let replayed = get(&replay, "/missing");
+        assert!(replayed.starts_with("HTTP/1.1 404 Not Found"), "{}", replayed);
+        assert_eq!(body(&missing), body(&replayed));
+
+        let options = super::Options { record: Some(dir.join("config.txt")), ..Default::default() };
+        assert!(matches!(super::MockServer::start("127.0.0.1:0", "", options), Err(super::Error::Options(_))));
+    }
+
+    #[test]
+    fn inline_text() {
+        let options = mocks_options();
+        let config = "/ok\n`content-length;\"HTTP/1.1 200 OK\\r\\n\\r\\n\";\"{\\\"ok\\\":true}\"\n";
+        let server = super::MockServer::start("127.0.0.1:0", config, options).unwrap();
+        assert_eq!("HTTP/1.1 200 OK\r\ncontent-length: 11\r\n\r\n{\"ok\":true}", get(&server, "/ok"));
+    }
+
+    #[test]
+    fn wire_criteria() {
+        // the chunk sizes are only in the wire form
+        let config = "/upload\n@~^6\\s*$\n`content-length;\"HTTP/1.1 201 Created\\r\\n\\r\\n\"\n\n\
+            /upload\nhello world\n`content-length;\"HTTP/1.1 200 OK\\r\\n\\r\\n\"\n";
+        let server = super::MockServer::start("127.0.0.1:0", config, super::Options::default()).unwrap();
+        let send_chunked = |body: &str| {
+            let mut stream = TcpStream::connect(server.address()).unwrap();
+            write!(stream, "POST /upload HTTP/1.1\r\nConnection: close\r\nTransfer-Encoding: chunked\r\n\r\n{}", body).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(send_chunked("5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n").starts_with("HTTP/1.1 201"));
+        assert!(send_chunked("b\r\nhello world\r\n0\r\n\r\n").starts_with("HTTP/1.1 200"));
+        assert!(post(&server, "/upload", "hello world").starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn template() {
+        let config = "~^GET /orders/(?P<id>\\d+)\n`template;`content-length;\"HTTP/1.1 200 OK\\r\\n\\r\\n{\\\"id\\\":{{id}}}\"\n\n\
+            ~^GET /raw/(?P<id>\\d+)\n`content-length;\"HTTP/1.1 200 OK\\r\\n\\r\\n{{id}}\"\n";
+        let server = super::MockServer::start("127.0.0.1:0", config, super::Options::default()).unwrap();
+        assert!(get(&server, "/orders/42").ends_with("\r\n\r\n{\"id\":42}"));
+        // without the option the placeholders are sent as they are
+        assert!(get(&server, "/raw/42").ends_with("\r\n\r\n{{id}}"));
+    }
+
+    #[test]
+    fn virtual_clock() {
+        let config = "/timed\n`after 60000;headers;hello.html\n\n/timed\nheaders;404.html\n\n/reset\n`reset;headers;404.html\n";
+        let options = super::Options { virtual_clock: true, ..mocks_options() };
+        let server = super::MockServer::start("127.0.0.1:0", config, options).unwrap();
+        assert!(get(&server, "/timed").contains("404"));
+        server.advance_clock(Duration::from_millis(59999)).unwrap();
+        assert!(get(&server, "/timed").contains("404"));
+        assert!(post(&server, "/__mockside/clock/advance", "1").starts_with("HTTP/1.1 200"));
+        assert!(get(&server, "/timed").contains("Iron Mockside"));
+
+        get(&server, "/reset");
+        assert!(get(&server, "/timed").contains("404"));
+        server.advance_clock(Duration::from_millis(60000)).unwrap();
+        assert!(get(&server, "/timed").contains("Iron Mockside"));
+        assert!(post(&server, "/__mockside/reset", "").starts_with("HTTP/1.1 200"));
+        assert!(get(&server, "/timed").contains("404"));
+
+        let clock = get(&server, "/__mockside/clock");
+        assert!(clock.ends_with("{\"elapsed\":0,\"time\":120000,\"timers\":{},\"virtual\":true}"), "{}", clock);
+        server.set_virtual_clock(false);
+        // the real clock continues from the virtual time, the timer is not back before the reset
+        let clock = get(&server, "/__mockside/clock");
+        let clock: serde_json::Value = serde_json::from_str(&clock[clock.find('{').unwrap()..]).unwrap();
+        assert!(clock["time"].as_u64().unwrap() >= 120000, "{}", clock);
+        assert!(clock["elapsed"].as_u64().unwrap() < 1000, "{}", clock);
+        assert!(matches!(server.advance_clock(Duration::from_millis(1)), Err(super::Error::Options(_))));
+        assert!(post(&server, "/__mockside/clock/advance?ms=1", "").starts_with("HTTP/1.1 409"));
+        assert!(put(&server, "/__mockside/clock", "virtual").starts_with("HTTP/1.1 200"));
+        assert!(post(&server, "/__mockside/clock/advance?ms=1", "").starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn named_timers() {
+        let config = "/payment/start\n`reset [payment];headers;404.html\n\n/export/start\n`reset [export];headers;404.html\n\n\
+            /payment/status\n`after 1000 [payment];headers;hello.html\n\n/export/status\n`after 1000 [export];headers;hello.html\n\n\
+            /status\nheaders;404.html\n";
+        let options = super::Options { virtual_clock: true, ..mocks_options() };
+        let server = super::MockServer::start("127.0.0.1:0", config, options).unwrap();
+        get(&server, "/payment/start");
+        server.advance_clock(Duration::from_millis(600)).unwrap();
+        get(&server, "/export/start");
+        server.advance_clock(Duration::from_millis(600)).unwrap();
+        assert!(get(&server, "/payment/status").contains("Iron Mockside"));
+        assert!(get(&server, "/export/status").contains("404"));
+        let clock = get(&server, "/__mockside/clock");
+        assert!(clock.contains("\"timers\":{\"export\":600,\"payment\":1200}"), "{}", clock);
+
+        post(&server, "/__mockside/reset?timer=payment", "");
+        server.advance_clock(Duration::from_millis(400)).unwrap();
+        assert!(get(&server, "/payment/status").contains("404"));
+        assert!(get(&server, "/export/status").contains("Iron Mockside"));
+        server.reset_timer();
+        assert!(get(&server, "/export/status").contains("404"));
+
+        let config = "/status\n`after 1000 [payment];headers;hello.html\n";
+        assert!(super::MockServer::start("127.0.0.1:0", config, super::Options::default()).is_err());
+    }
+
+    #[test]
+    fn sessions() {
+        let config = "/login\n`profile [logged-in];headers;404.html\n\n/orders\n`[logged-in];headers;hello.html\n";
+        let options = super::Options { session: Some(String::from("header:X-Session")), ..mocks_options() };
+        let server = super::MockServer::start("127.0.0.1:0", config, options).unwrap();
+        let get_in = |session: &str, path: &str| {
+            let mut stream = TcpStream::connect(server.address()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nX-Session: {}\r\nConnection: close\r\n\r\n", path, session).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        get_in("a", "/login");
+        assert!(get_in("a", "/orders").contains("Iron Mockside"));
+        assert!(get_in("b", "/orders").contains("404"));
+        assert!(get(&server, "/orders").contains("404"));
+        assert_eq!("logged-in", server.session_profile("a"));
+        assert_eq!("default", server.session_profile("b"));
+        assert_eq!("default", server.profile());
+
+        assert!(get_in("b", "/__mockside/profile").ends_with("{\"profile\":\"default\"}"));
+        // reading the state of a new session doesn't create it
+        assert!(get_in("c", "/__mockside/profile").ends_with("{\"profile\":\"default\"}"));
+        assert!(get_in("c", "/__mockside/clock").contains("\"elapsed\":0"));
+        assert_eq!("default", server.session_profile("d"));
+        let sessions = get(&server, "/__mockside/sessions");
+        assert!(sessions.ends_with("[{\"profile\":\"logged-in\",\"session\":\"a\"},{\"profile\":\"default\",\"session\":\"b\"}]"), "{}", sessions);
+        assert_eq!(Some("a"), server.requests()[0].session.as_deref());
+        assert_eq!(vec!["default", "logged-in"], server.requests().iter().take(2).map(|e| e.profile.as_str()).collect::<Vec<_>>());
+
+        server.set_session_profile("b", "logged-in").unwrap();
+        assert!(get_in("b", "/orders").contains("Iron Mockside"));
+        server.clear_sessions();
+        assert!(get_in("a", "/orders").contains("404"));
+
+        let options = super::Options { session: Some(String::from("query:id")), ..Default::default() };
+        assert!(matches!(super::MockServer::start("127.0.0.1:0", config, options), Err(super::Error::Options(_))));
+    }
+
+    #[test]
+    fn times_and_nth() {
+        let config = "/pay\n`times 2;\"HTTP/1.1 503 Service Unavailable\\r\\n\\r\\n\"\n\n\
+            /pay\n`nth 2;\"HTTP/1.1 202 Accepted\\r\\n\\r\\n\"\n\n\
+            /restart\n`reset;headers;404.html\n\n\
+            /pay\nheaders;hello.html\n";
+        let options = mocks_options();
+        let server = super::MockServer::start("127.0.0.1:0", config, options).unwrap();
+        let statuses = |count: usize| -> Vec<String> {
+            (0..count).map(|_| get(&server, "/pay").lines().next().unwrap().to_string()).collect()
+        };
+        assert_eq!(vec!["HTTP/1.1 503 Service Unavailable", "HTTP/1.1 503 Service Unavailable", "HTTP/1.1 200 OK", "HTTP/1.1 202 Accepted", "HTTP/1.1 200 OK"], statuses(5));
+
+        let mocks = get(&server, "/__mockside/mocks");
+        assert!(mocks.contains("\"hits\":2,\"id\":1"), "{}", mocks);
+        assert!(mocks.contains("\"hits\":3,\"id\":2"), "{}", mocks);
+        get(&server, "/restart");
+        assert!(get(&server, "/pay").starts_with("HTTP/1.1 503"));
+        post(&server, "/__mockside/reset", "");
+        assert!(get(&server, "/__mockside/mocks").contains("\"hits\":0,\"id\":1"));
+    }
+
+    #[test]
+    fn scenarios() {
+        let config = "/cart\n`scenario checkout [start] -> [cart];\"HTTP/1.1 201 Created\\r\\n\\r\\n\"\n\n\
+            /pay\n`scenario checkout [cart] -> [paid];\"HTTP/1.1 202 Accepted\\r\\n\\r\\n\"\n\n\
+            /order\n`scenario checkout [paid];headers;hello.html\n\n\
+            /export/status\n`scenario export [running];headers;hello.html\n\n\
+            /export\n`scenario export -> [running];\"HTTP/1.1 202 Accepted\\r\\n\\r\\n\"\n";
+        let options = mocks_options();
+        let server = super::MockServer::start("127.0.0.1:0", config, options).unwrap();
+        assert!(get(&server, "/pay").contains("404"));
+        assert!(get(&server, "/cart").starts_with("HTTP/1.1 201"));
+        assert!(get(&server, "/export").starts_with("HTTP/1.1 202"));
+        assert!(get(&server, "/pay").starts_with("HTTP/1.1 202"));
+        assert!(get(&server, "/order").contains("Iron Mockside"));
+        assert!(get(&server, "/export/status").contains("Iron Mockside"));
+        assert_eq!("paid", server.scenario_state("checkout"));
+
+        let scenarios = get(&server, "/__mockside/scenarios");
+        assert!(scenarios.ends_with("{\"checkout\":\"paid\",\"export\":\"running\"}"), "{}", scenarios);
+        assert!(get(&server, "/__mockside/mocks").contains("\"scenario\":{\"name\":\"checkout\",\"next\":\"cart\",\"state\":\"start\"}"));
+        server.reset_timer();
+        assert_eq!("start", server.scenario_state("checkout"));
+        assert!(get(&server, "/order").contains("404"));
+
+        let config = "/pay\n`scenario checkout [cart] -> [paid];headers;hello.html\n";
+        assert!(super::MockServer::start("127.0.0.1:0", config, super::Options::default()).is_err());
+    }
+
+    #[test]
+    fn unreadable_response_file() {
+        let dir = TestDir::new("unreadable");
+        std::fs::create_dir(dir.join("directory")).unwrap();
+        let options = super::Options { base_dir: dir.to_path_buf(), workers: 1, ..Default::default() };
+        let server = super::MockServer::start("127.0.0.1:0", "/dir\ndirectory\n", options).unwrap();
+        assert!(get(&server, "/dir").starts_with("HTTP/1.1 500"));
+        // the only worker is still serving
+        assert!(get(&server, "/dir").starts_with("HTTP/1.1 500"));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let options = mocks_options();
+        match super::MockServer::start("127.0.0.1:0", "/hello\nheaders;missing.html\n", options) {
+            Err(super::Error::Config(errors)) => {
+                assert_eq!("Could not find response file", errors[0].message);
+                assert_eq!((1, "missing.html"), (errors[0].line, errors[0].text.as_str()));
+            },
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("missing file not detected"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::net::IpAddr;
+
+use crate::request::Request;
+
+/// What identifies the session of a request, each session has its own profile and timers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SessionKey {
+    Header(String),
+    Cookie(String),
+    ClientIp,
+}
+
+impl SessionKey {
+    /// Parses a key like `header:X-Test-Session`, `cookie:session` or `ip`
+    pub(crate) fn parse(text: &str) -> Result<SessionKey, &'static str> {
+        let (kind, name) = match text.find(':') {
+            Some(colon) => (&text[..colon], text[colon + 1..].trim()),
+            None => (text, ""),
+        };
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "ip" if name.is_empty() => Ok(SessionKey::ClientIp),
+            "header" | "cookie" if name.is_empty() => Err("the session header or cookie has no name"),
+            "header" => Ok(SessionKey::Header(name.to_string())),
+            "cookie" => Ok(SessionKey::Cookie(name.to_string())),
+            _ => Err("the session is identified by header:NAME, cookie:NAME or ip"),
+        }
+    }
+
+    /// The session of the request, `None` if it doesn't have the header or the cookie
+    pub(crate) fn session(&self, request: &Request, client: Option<IpAddr>) -> Option<String> {
+        match self {
+            SessionKey::Header(name) => request.header(name).map(String::from),
+            SessionKey::ClientIp => client.map(|ip| ip.to_string()),
+            SessionKey::Cookie(name) => request.headers.iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case("Cookie"))
+                .flat_map(|(_, v)| v.split(';'))
+                .filter_map(|cookie| {
+                    let (cookie_name, value) = cookie.split_at(cookie.find('=')?);
+                    Some((cookie_name.trim(), value[1..].trim()))
+                })
+                .find(|(cookie_name, _)| cookie_name == name)
+                .map(|(_, value)| value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::request::RequestReader;
+
+    use super::SessionKey;
+
+    #[test]
+    fn parse_key() {
+        assert_eq!(Ok(SessionKey::Header(String::from("X-Session"))), SessionKey::parse("header:X-Session"));
+        assert_eq!(Ok(SessionKey::Cookie(String::from("sid"))), SessionKey::parse("Cookie: sid"));
+        assert_eq!(Ok(SessionKey::ClientIp), SessionKey::parse("ip"));
+        assert!(SessionKey::parse("header:").is_err());
+        assert!(SessionKey::parse("query:id").is_err());
+    }
+
+    #[test]
+    fn session_of_request() {
+        let wire = b"GET / HTTP/1.1\r\nX-Session: worker-1\r\nCookie: theme=dark\r\nCookie: a=1; sid=abc\r\n\r\n";
+        let request = RequestReader::new(io::Cursor::new(wire.to_vec()), 1024).read_request().unwrap().unwrap();
+        let client = Some("10.0.0.7".parse().unwrap());
+        assert_eq!(Some(String::from("worker-1")), SessionKey::Header(String::from("x-session")).session(&request, client));
+        assert_eq!(Some(String::from("abc")), SessionKey::Cookie(String::from("sid")).session(&request, client));
+        assert_eq!(None, SessionKey::Cookie(String::from("id")).session(&request, client));
+        assert_eq!(Some(String::from("10.0.0.7")), SessionKey::ClientIp.session(&request, client));
+    }
+}
@@ -0,0 +1,32 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory for the files of a test, removed with its content when dropped,
+/// also when the test fails
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+    /// A new empty directory in the temporary directory, the name is unique to the test
+    pub(crate) fn new(name: &str) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("iron-mockside-{}-{}", name, std::process::id()));
+        // left over by an interrupted run
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
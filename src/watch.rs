@@ -0,0 +1,51 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::*;
+
+use crate::server::Server;
+
+/// Modification time, size and hash of the content of a file
+type Stamp = (SystemTime, u64, u64);
+
+/// The stamps of the files, `None` for the missing ones.
+/// A file rewritten with the same size can keep its modification time on the file systems
+/// with a coarse one, only the content tells the change.
+fn snapshot(files: Vec<PathBuf>) -> Vec<(PathBuf, Option<Stamp>)> {
+    files.into_iter().map(|file| {
+        let stamp = fs::metadata(&file).and_then(|m| {
+            let mut hasher = DefaultHasher::new();
+            fs::read(&file)?.hash(&mut hasher);
+            Ok((m.modified()?, m.len(), hasher.finish()))
+        }).ok();
+        (file, stamp)
+    }).collect()
+}
+
+/// Checks the config and response files at every interval and reloads the
+/// config when one of them changes, until the server shuts down.
+/// A config failing the checks is reported and the current one kept.
+pub(crate) fn watch(server: &Server, interval: Duration) {
+    let mut last = snapshot(server.watched_files());
+    loop {
+        thread::sleep(interval);
+        if server.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        let current = snapshot(server.watched_files());
+        if current == last {
+            continue;
+        }
+        debug!("Change detected in {:?}", current.iter().filter(|f| !last.contains(f)).map(|f| &f.0).collect::<Vec<_>>());
+        if let Err(e) = server.reload() {
+            error!("Keeping the current config, {}", e);
+        }
+        // the new config can reference other files
+        last = snapshot(server.watched_files());
+    }
+}